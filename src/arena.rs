@@ -0,0 +1,197 @@
+use crate::cell::*;
+use std::vec::Vec;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/*
+ * Handle to a cell living in a CellArena.
+ * The generation is bumped every time a slot is freed, so a handle to a dead cell
+ * never resolves to whatever cell later reuses its slot.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CellId {
+    index: u32,
+    generation: u32,
+}
+
+impl CellId {
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct Slot {
+    generation: u32,
+    cell: Option<Cell>,
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CellArena {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    len: usize,
+}
+
+impl CellArena {
+    pub fn new() -> CellArena {
+        CellArena { slots: Vec::new(), free: Vec::new(), len: 0 }
+    }
+
+    pub fn with_capacity(capacity: usize) -> CellArena {
+        CellArena { slots: Vec::with_capacity(capacity), free: Vec::new(), len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Dead slots are reused before the arena grows
+    pub fn insert(&mut self, cell: Cell) -> CellId {
+        self.len += 1;
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index as usize];
+            slot.cell = Some(cell);
+            CellId { index, generation: slot.generation }
+        } else {
+            self.slots.push(Slot { generation: 0, cell: Some(cell) });
+            CellId { index: (self.slots.len() - 1) as u32, generation: 0 }
+        }
+    }
+
+    pub fn remove(&mut self, id: CellId) -> Option<Cell> {
+        let slot = self.slots.get_mut(id.index())?;
+        if slot.generation != id.generation {
+            return None;
+        }
+
+        let ret = slot.cell.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(id.index);
+        self.len -= 1;
+        Some(ret)
+    }
+
+    pub fn contains(&self, id: CellId) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn get(&self, id: CellId) -> Option<&Cell> {
+        match self.slots.get(id.index()) {
+            Some(slot) if slot.generation == id.generation => slot.cell.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, id: CellId) -> Option<&mut Cell> {
+        match self.slots.get_mut(id.index()) {
+            Some(slot) if slot.generation == id.generation => slot.cell.as_mut(),
+            _ => None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.cell.take().is_some() {
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free.push(index as u32);
+            }
+        }
+        self.len = 0;
+    }
+
+    // Live cells in slot order
+    pub fn iter(&self) -> impl Iterator<Item = (CellId, &Cell)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.cell.as_ref().map(|cell| (CellId { index: index as u32, generation: slot.generation }, cell))
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (CellId, &mut Cell)> {
+        self.slots.iter_mut().enumerate().filter_map(|(index, slot)| {
+            let generation = slot.generation;
+            slot.cell.as_mut().map(|cell| (CellId { index: index as u32, generation }, cell))
+        })
+    }
+
+    pub fn ids(&self) -> Vec<CellId> {
+        self.iter().map(|(id, _)| id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn cell(rng: &mut rand_chacha::ChaCha8Rng, age: u32) -> Cell {
+        let mut ret = Cell::with_genes(Vec::new(), rng);
+        ret.age = age; // Something to tell cells apart by
+        ret
+    }
+
+    #[test]
+    fn removed_handles_stay_dead_after_reuse() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(1);
+        let mut arena = CellArena::new();
+        let old = arena.insert(cell(&mut rng, 1));
+
+        assert_eq!(arena.remove(old).map(|cell| cell.age), Some(1));
+        assert!(arena.get(old).is_none());
+        assert!(arena.get_mut(old).is_none());
+        assert!(arena.remove(old).is_none());
+
+        let new = arena.insert(cell(&mut rng, 2));
+        assert_eq!(new.index(), old.index());
+        assert_ne!(new, old);
+        assert!(arena.get(old).is_none());
+        assert!(arena.remove(old).is_none());
+        assert_eq!(arena.get(new).map(|cell| cell.age), Some(2));
+    }
+
+    #[test]
+    fn clear_invalidates_every_handle() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(1);
+        let mut arena = CellArena::new();
+        let ids: Vec<CellId> = (0..4).map(|age| arena.insert(cell(&mut rng, age))).collect();
+        arena.remove(ids[1]);
+
+        arena.clear();
+        assert!(arena.is_empty());
+        let reused: Vec<CellId> = (0..4).map(|age| arena.insert(cell(&mut rng, age))).collect();
+        for id in ids.iter() {
+            assert!(!arena.contains(*id));
+            assert!(arena.remove(*id).is_none());
+        }
+        assert!(reused.iter().all(|id| arena.contains(*id)));
+    }
+
+    #[test]
+    fn len_follows_removes_and_reuse() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(1);
+        let mut arena = CellArena::new();
+        let ids: Vec<CellId> = (0..5).map(|age| arena.insert(cell(&mut rng, age))).collect();
+        assert_eq!(arena.len(), 5);
+
+        arena.remove(ids[0]);
+        arena.remove(ids[3]);
+        arena.remove(ids[3]); // Already gone, doesn't count twice
+        assert_eq!(arena.len(), 3);
+
+        arena.insert(cell(&mut rng, 5));
+        assert_eq!(arena.len(), 4);
+        assert_eq!(arena.len(), arena.iter().count());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::cell::*;
+use std::vec::Vec;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+// Where a connection reads from, indices into Brain::get_inputs and Brain::get_internals
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Source {
+    Sensor(usize),
+    Internal(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Connection {
+    pub source: Source,
+    pub weight: f64, // What the source's value is multiplied by, from the gene's fixed point weight
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Neuron<T> {
+    pub kind: T,
+    pub incoming: Vec<Connection>,
+    pub bias: f64, // Mean of the biases of the genes feeding the neuron, so fan-in doesn't pile them up
+}
+
+/*
+ * A genome decoded into the network it describes, built once when the genome changes.
+ * Only neurons that lead to an output are kept, and each sensor is listed once however many genes read it.
+ * A neuron can have any number of incoming connections, their weighted values are summed before its activation.
+ * Internal neurons are in topological order so one pass evaluates them.
+ * A connection that closes a cycle (a neuron feeding itself included) reads the value its source had
+ * at the end of the previous evaluation instead, which is what makes recurrent networks and memory possible.
+ */
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Brain {
+    inputs: Vec<InputNeurons>,
+    internals: Vec<Neuron<InternalNeurons>>,
+    outputs: Vec<Neuron<OutputNeurons>>, // Only the connected ones, in OutputNeurons order
+}
+
+// A neuron as the genes name it, before the brain gives it an index
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Node {
+    Input(InputNeurons),
+    Internal(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mark {
+    Unvisited,
+    Visiting,
+    Done,
+}
+
+impl Brain {
+    pub fn new(genes: &[Gene]) -> Brain {
+        let mut output_links: Vec<Vec<(Node, f64)>> = vec![Vec::new(); OUTPUT_NEURON_COUNT];
+        let mut internal_links: Vec<Vec<(Node, f64)>> = vec![Vec::new(); INTERNAL_NEURON_COUNT];
+        // Summed and counted here, divided out once every gene is in
+        let mut output_bias = [(0.0, 0); OUTPUT_NEURON_COUNT];
+        let mut internal_bias = [(0.0, 0); INTERNAL_NEURON_COUNT];
+
+        for gene in genes.iter().filter(|gene| gene_in_range(**gene)) {
+            let (input, output, weight, bias, input_is_internal, output_is_internal) = decode_gene(*gene);
+            let weight = weight_to_f64(weight);
+            let source = if input_is_internal {
+                Node::Internal(input as usize)
+            } else {
+                Node::Input(InputNeurons::from_int(input))
+            };
+
+            let (links, total) = if output_is_internal {
+                (&mut internal_links[output as usize], &mut internal_bias[output as usize])
+            } else {
+                (&mut output_links[output as usize], &mut output_bias[output as usize])
+            };
+            total.0 += weight_to_f64(bias);
+            total.1 += 1;
+            // Several genes making the same connection are one connection with their weights added up
+            match links.iter_mut().find(|(node, _)| *node == source) {
+                Some((_, total)) => *total += weight,
+                None => links.push((source, weight)),
+            }
+        }
+
+        let mut marks = vec![Mark::Unvisited; INTERNAL_NEURON_COUNT];
+        let mut order: Vec<usize> = Vec::new();
+        for links in output_links.iter() {
+            for (node, _) in links {
+                if let Node::Internal(neuron) = *node {
+                    visit(neuron, &internal_links, &mut marks, &mut order);
+                }
+            }
+        }
+
+        let mut ret = Brain::default();
+        let mut position = vec![0; INTERNAL_NEURON_COUNT];
+        for (i, neuron) in order.iter().enumerate() {
+            position[*neuron] = i;
+        }
+        for neuron in order.iter() {
+            let incoming = internal_links[*neuron].iter().map(|(node, weight)| Connection { source: ret.source(*node, &position), weight: *weight }).collect();
+            ret.internals.push(Neuron { kind: InternalNeurons::from_int(*neuron as i32), incoming, bias: mean(internal_bias[*neuron]) });
+        }
+        for (output, links) in output_links.iter().enumerate() {
+            if links.is_empty() {
+                continue;
+            }
+            let incoming = links.iter().map(|(node, weight)| Connection { source: ret.source(*node, &position), weight: *weight }).collect();
+            ret.outputs.push(Neuron { kind: OutputNeurons::from_int(output as i32), incoming, bias: mean(output_bias[output]) });
+        }
+
+        ret
+    }
+
+    fn source(&mut self, node: Node, position: &[usize]) -> Source {
+        match node {
+            Node::Input(neuron) => {
+                let index = self.inputs.iter().position(|n| *n == neuron).unwrap_or_else(|| {
+                    self.inputs.push(neuron);
+                    self.inputs.len() - 1
+                });
+                Source::Sensor(index)
+            },
+            Node::Internal(neuron) => Source::Internal(position[neuron]),
+        }
+    }
+
+    // The sensors evaluate wants values for, in this order
+    pub fn get_inputs(&self) -> &[InputNeurons] {
+        &self.inputs
+    }
+
+    pub fn get_internals(&self) -> &[Neuron<InternalNeurons>] {
+        &self.internals
+    }
+
+    pub fn get_outputs(&self) -> &[Neuron<OutputNeurons>] {
+        &self.outputs
+    }
+
+    /*
+     * Level of every connected output, sensors lines up with get_inputs.
+     * memory holds the internal neuron values from the previous evaluation and is updated in place,
+     * anything the wrong length (e.g. a new cell's empty Vec) starts again from all 0.0.
+     */
+    pub fn evaluate(&self, sensors: &[f64], memory: &mut Vec<f64>) -> Vec<(OutputNeurons, f64)> {
+        if memory.len() != self.internals.len() {
+            *memory = vec![0.0; self.internals.len()];
+        }
+        let values = memory;
+        let sum = |incoming: &[Connection], bias: f64, values: &[f64]| -> f64 {
+            bias + incoming.iter().map(|connection| match connection.source {
+                // A broken sensor shouldn't take the whole network down with it
+                Source::Sensor(i) if sensors[i].is_finite() => sensors[i] * connection.weight,
+                Source::Sensor(_) => 0.0,
+                Source::Internal(i) => values[i] * connection.weight,
+            }).sum::<f64>()
+        };
+
+        // Sources earlier in the order already hold this pass's value, later ones still hold the last one
+        for i in 0..self.internals.len() {
+            let neuron = &self.internals[i];
+            values[i] = neuron.kind.activate(sum(&neuron.incoming, neuron.bias, values), neuron.incoming.len().max(1));
+        }
+
+        let ret = self.outputs.iter()
+            .map(|neuron| (neuron.kind, neuron.kind.activate(sum(&neuron.incoming, neuron.bias, values))))
+            .collect();
+
+        // A NaN or infinity carried over would stick around in a loop forever
+        for value in values.iter_mut() {
+            if !value.is_finite() {
+                *value = 0.0;
+            }
+        }
+        ret
+    }
+}
+
+fn mean((sum, count): (f64, usize)) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        sum / count as f64
+    }
+}
+
+// Depth first, a neuron goes into order after everything feeding it
+fn visit(neuron: usize, links: &[Vec<(Node, f64)>], marks: &mut [Mark], order: &mut Vec<usize>) {
+    if marks[neuron] != Mark::Unvisited {
+        return;
+    }
+
+    marks[neuron] = Mark::Visiting;
+    for (node, _) in links[neuron].iter() {
+        if let Node::Internal(source) = *node {
+            visit(source, links, marks, order);
+        }
+    }
+    marks[neuron] = Mark::Done;
+    order.push(neuron);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: i32 = 0; // Tanh
+    const B: i32 = 3; // Abs
+
+    // sensor -> A -> B -> Move, with B feeding back into A
+    fn cycle() -> Brain {
+        let one = weight_from_f64(1.0);
+        Brain::new(&[
+            encode_gene(InputNeurons::Age as i32, A, one, 0, false, true),
+            encode_gene(A, B, one, 0, true, true),
+            encode_gene(B, A, one, 0, true, true),
+            encode_gene(B, OutputNeurons::Move as i32, one, 0, true, false),
+        ])
+    }
+
+    #[test]
+    fn a_cycle_builds_and_evaluates() {
+        let brain = cycle();
+        let kinds: Vec<InternalNeurons> = brain.get_internals().iter().map(|neuron| neuron.kind).collect();
+        assert_eq!(kinds, [InternalNeurons::from_int(A), InternalNeurons::from_int(B)]);
+
+        let mut memory = Vec::new();
+        let outputs = brain.evaluate(&[0.5], &mut memory);
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].0, OutputNeurons::Move);
+    }
+
+    #[test]
+    fn the_back_edge_reads_the_previous_evaluation() {
+        let brain = cycle();
+        let mut memory = Vec::new();
+        brain.evaluate(&[0.5], &mut memory);
+        let first = memory.clone();
+        assert_eq!(first, [0.5f64.tanh(), 0.5f64.tanh().abs().tanh()]);
+
+        brain.evaluate(&[0.5], &mut memory);
+        assert_eq!(memory[0], (0.5 + first[1]).tanh());
+        assert_eq!(memory[1], memory[0].abs().tanh());
+    }
+
+    #[test]
+    fn fresh_memory_starts_from_zero() {
+        let brain = cycle();
+        let mut used = Vec::new();
+        let first = brain.evaluate(&[0.5], &mut used);
+        brain.evaluate(&[0.5], &mut used);
+
+        // What the back edge reads on a first evaluation is 0.0, so a fresh memory gives the first result again
+        assert_eq!(brain.evaluate(&[0.5], &mut Vec::new()), first);
+        assert_ne!(brain.evaluate(&[0.5], &mut used), first);
+    }
+}
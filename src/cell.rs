@@ -1,21 +1,97 @@
-use std::{ptr::null_mut, iter::FlatMap, ops::Neg};
-#[allow(dead_code)]
-
-use std::{vec::Vec, ops::Index, clone::Clone, marker::Copy, collections::btree_map::OccupiedEntry};
+use std::ops::Neg;
+use std::{vec::Vec, ops::Index, clone::Clone, marker::Copy};
 use rand::*;
 use crate::world::*;
+use crate::genome;
+use crate::mutation::*;
+use crate::brain::*;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
 
-const MAGIC_GENE_DECISION_WORD: u16 = 0x4C65;
+pub const INPUT_NEURON_COUNT: usize = 25;
+pub const OUTPUT_NEURON_COUNT: usize = 11;
+pub const INTERNAL_NEURON_COUNT: usize = 8;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Compass {
     North = 0,
     South,
     East,
     West,
+
+    // Diagonals, the hex lattice uses these instead of North and South
+    NorthEast,
+    NorthWest,
+    SouthEast,
+    SouthWest,
+}
+
+impl Compass {
+    // Only the four cardinal directions
+    pub fn from_int(integer: i32) -> Self {
+        match integer.rem_euclid(4) {
+            0 => Self::North,
+            1 => Self::South,
+            2 => Self::East,
+            3 => Self::West,
+            _ => Self::East,
+        }
+    }
+
+    // North is +y and East is +x, matching the *Forward sensors
+    // This is the square lattice step, on the hex lattice use Grid::direction_offset
+    pub fn offset(&self) -> Position<isize> {
+        match *self {
+            Self::North => Position::new(0, 1),
+            Self::South => Position::new(0, -1),
+            Self::East => Position::new(1, 0),
+            Self::West => Position::new(-1, 0),
+            Self::NorthEast => Position::new(1, 1),
+            Self::NorthWest => Position::new(-1, 1),
+            Self::SouthEast => Position::new(1, -1),
+            Self::SouthWest => Position::new(-1, -1),
+        }
+    }
+
+    pub fn from_offset(offset: Position<isize>) -> Option<Self> {
+        match (offset.x.signum(), offset.y.signum()) {
+            (0, 1) => Some(Self::North),
+            (0, -1) => Some(Self::South),
+            (1, 0) => Some(Self::East),
+            (-1, 0) => Some(Self::West),
+            (1, 1) => Some(Self::NorthEast),
+            (-1, 1) => Some(Self::NorthWest),
+            (1, -1) => Some(Self::SouthEast),
+            (-1, -1) => Some(Self::SouthWest),
+            _ => None,
+        }
+    }
+
+    pub fn opposite(&self) -> Self {
+        match *self {
+            Self::North => Self::South,
+            Self::South => Self::North,
+            Self::East => Self::West,
+            Self::West => Self::East,
+            Self::NorthEast => Self::SouthWest,
+            Self::NorthWest => Self::SouthEast,
+            Self::SouthEast => Self::NorthWest,
+            Self::SouthWest => Self::NorthEast,
+        }
+    }
+
+    // Steps clockwise around directions (counter clockwise if negative), which should be one of the Grid::directions lists
+    // A direction missing from the list turns from the first one
+    pub fn turn(&self, directions: &[Compass], steps: isize) -> Self {
+        let start = directions.iter().position(|d| d == self).unwrap_or(0) as isize;
+        directions[(start + steps).rem_euclid(directions.len() as isize) as usize]
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum InputNeurons {
     // Spacial information. 
     FoodLeftRight = 0,
@@ -54,9 +130,13 @@ pub enum InputNeurons {
     // misc
     Random,
     Oscilator,
+
+    // Body
+    Hunger,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum InternalNeurons {
     // Hyperbolic trig
     Tanh = 0,
@@ -72,6 +152,7 @@ pub enum InternalNeurons {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum OutputNeurons {
     // Misc
     SetOscilator,
@@ -86,11 +167,16 @@ pub enum OutputNeurons {
 
     // Violence
     KillFoward,
+
+    // Turning, only changes the rotation
+    TurnLeft,
+    TurnRight,
+    TurnAround,
 }
 
 impl OutputNeurons {
     pub fn from_int(integer: i32) -> Self {
-        match integer % 8 {
+        match integer % 11 {
             0 => Self::SetOscilator,
             1 => Self::EmitPheromone,
             2 => Self::SetResponsiveness,
@@ -99,9 +185,23 @@ impl OutputNeurons {
             5 => Self::MoveY,
             6 => Self::MoveRandom,
             7 => Self::KillFoward,
+            8 => Self::TurnLeft,
+            9 => Self::TurnRight,
+            10 => Self::TurnAround,
             _ => Self::KillFoward,
         }
     }
+
+    // Squashes the summed signals feeding this output into -1.0..=1.0
+    pub fn activate(&self, sum: f64) -> f64 {
+        // Internal neurons can produce NaN (sqrt of a negative sum), which would poison the actions
+        let level = sum.tanh();
+        if level.is_nan() {
+            0.0
+        } else {
+            level
+        }
+    }
 }
 
 impl InternalNeurons {
@@ -119,41 +219,15 @@ impl InternalNeurons {
         }
     }
 
-    pub fn handle(&self, inputs: &Vec<GeneInput>, all_inputs: &Vec<Vec<GeneInput>>) -> f64 {
-        let mut sum = 0.0;
-        let mut ctr = 0;
-
-        for i in inputs.as_slice() {
-            sum += match *i {
-                GeneInput::Input(f) => {
-                    if f.is_nan() == false && f.is_finite() {
-                        f
-                    } else {
-                        0
-                    }
-                },
-                GeneInput::Internal(neuron, cell_index) => {
-                    if neuron == self {
-                        0.0
-                    } else {
-                        neuron.handle(all_inputs.index(cell_index), all_inputs)
-                    }
-                },
-                GeneInput::Empty => {
-                    0.0
-                },
-            };
-
-            ctr += 1;
-        }
-
+    // count is how many connections went into sum, Avg needs it
+    pub fn activate(&self, sum: f64, count: usize) -> f64 {
         match *self {
             Self::Tanh => sum.tanh(),
             Self::Cosh => sum.cosh(),
             Self::Sinh => sum.sinh(),
             Self::Abs => sum.abs().tanh(),
             Self::Neg => sum.neg().tanh(),
-            Self::Avg => sum/(ctr as f64),
+            Self::Avg => sum/(count as f64),
             Self::Sqrt => sum.sqrt(),
             Self::InverseSqrt => 1.0/sum.sqrt(),
         }
@@ -162,7 +236,7 @@ impl InternalNeurons {
 
 impl InputNeurons {
     pub fn from_int(integer: i32) -> Self {
-        match integer % 24 {
+        match integer % INPUT_NEURON_COUNT as i32 {
             0 => Self::FoodLeftRight,
             1 => Self::FoodUpDown,
             2 => Self::FoodForward,
@@ -187,294 +261,52 @@ impl InputNeurons {
             21 => Self::GeneticSimilarity,
             22 => Self::Random,
             23 => Self::Oscilator,
+            24 => Self::Hunger,
             _ => Self::Random
         }
     }
 
-    pub fn handle(&mut self, cell: &Cell, grid: &Grid) -> f64 {
+    pub fn handle(&mut self, cell: &Cell, ctx: &SensorContext, rng: &mut impl Rng) -> f64 {
+        let grid = ctx.grid;
+        let pos = cell.position;
+        let forward = cell.rotation;
+
+        let food = |tile: Option<&Tile>| match tile {
+            Some(tile) if tile.has_food => 1.0,
+            _ => 0.0,
+        };
+        let pheromone = |tile: Option<&Tile>| match tile {
+            Some(tile) => tile.pheromone_level/ctx.pheromone.max,
+            None => 0.0,
+        };
+        // Walls and the grid edge (no neighbour there) count as blockage but not as population
+        let blockage = |tile: Option<&Tile>| match tile {
+            Some(tile) if tile.cell.is_none() && !tile.is_wall() => 0.0,
+            _ => 1.0,
+        };
+        let pop = |tile: Option<&Tile>| match tile {
+            Some(tile) if tile.cell.is_some() => 1.0,
+            _ => 0.0,
+        };
+
         match *self {
-            Self::FoodLeftRight => {
-                if grid[Position::new(cell.position.x + 1, cell.position.y)].has_food {
-                    1.0
-                } else if grid[Position::new(cell.position.x - 1, cell.position.y)].has_food {
-                    -1.0
-                } else {
-                    0.0
-                }
-            },
-            Self::FoodUpDown => {
-                if grid[Position::new(cell.position.x, cell.position.y + 1)].has_food {
-                    1.0
-                } else if grid[Position::new(cell.position.x, cell.position.y - 1)].has_food {
-                    -1.0
-                } else {
-                    0.0
-                }
-            },
-            Self::FoodForward => {
-                match cell.rotation {
-                    Compass::North => {
-                        if grid[Position::new(cell.position.x, cell.position.y + 1)].has_food {
-                            1.0
-                        } else if grid[Position::new(cell.position.x, cell.position.y - 1)].has_food {
-                            -1.0
-                        } else {
-                            0.0
-                        }
-                    },
-                    Compass::South => {
-                        if grid[Position::new(cell.position.x, cell.position.y - 1)].has_food {
-                            1.0
-                        } else if grid[Position::new(cell.position.x, cell.position.y + 1)].has_food {
-                            -1.0
-                        } else {
-                            0.0
-                        }
-                    },
-                    Compass::East => {
-                        if grid[Position::new(cell.position.x + 1, cell.position.y)].has_food {
-                            1.0
-                        } else if grid[Position::new(cell.position.x - 1, cell.position.y)].has_food {
-                            -1.0
-                        } else {
-                            0.0
-                        }
-                    },
-                    Compass::West => {
-                        if grid[Position::new(cell.position.x - 1, cell.position.y)].has_food {
-                            1.0
-                        } else if grid[Position::new(cell.position.x + 1, cell.position.y)].has_food {
-                            -1.0
-                        } else {
-                            0.0
-                        }
-                    },
-                }
-            },
-            Self::FoodDensity => {
-                let mut count = 0.0;
-                let pos = cell.position;
-                count += if grid[pos].has_food {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x, pos.y + 1)].has_food {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x + 1, pos.y + 1)].has_food {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x + 1, pos.y)].has_food {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x + 1, pos.y - 1)].has_food {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x, pos.y - 1)].has_food {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x - 1, pos.y - 1)].has_food {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x - 1, pos.y)].has_food {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x - 1, pos.y + 1)].has_food {
-                    1.0
-                } else {
-                    0.0
-                };
-                count/9.0
-            },
-            Self::PheromoneLeftRight => {
-                if grid[Position::new(cell.position.x + 1, cell.position.y)].pheromone_level != 0.0 {
-                    grid[Position::new(cell.position.x + 1, cell.position.y)].pheromone_level/50.0
-                } else if grid[Position::new(cell.position.x - 1, cell.position.y)].pheromone_level != 0.0 {
-                    grid[Position::new(cell.position.x - 1, cell.position.y)].pheromone_level/-50.0
-                } else {
-                    0.0
-                }
-            },
-            Self::PheromoneUpDown => {
-                if grid[Position::new(cell.position.x, cell.position.y + 1)].pheromone_level != 0.0 {
-                    grid[Position::new(cell.position.x, cell.position.y + 1)].pheromone_level/50.0
-                } else if grid[Position::new(cell.position.x, cell.position.y - 1)].pheromone_level != 0.0 {
-                    grid[Position::new(cell.position.x, cell.position.y - 1)].pheromone_level/-50.0
-                } else {
-                    0.0
-                }
-            },
-            Self::PheromoneForward => {
-                match cell.rotation {
-                    Compass::North => {
-                        if grid[Position::new(cell.position.x, cell.position.y + 1)].pheromone_level != 0.0 {
-                            grid[Position::new(cell.position.x, cell.position.y + 1)].pheromone_level/50.0
-                        } else if grid[Position::new(cell.position.x, cell.position.y - 1)].pheromone_level != 0.0 {
-                            grid[Position::new(cell.position.x, cell.position.y - 1)].pheromone_level/-50.0
-                        } else {
-                            0.0
-                        }
-                    },
-                    Compass::South => {
-                        if grid[Position::new(cell.position.x, cell.position.y - 1)].pheromone_level != 0.0 {
-                            grid[Position::new(cell.position.x, cell.position.y - 1)].pheromone_level/50.0
-                        } else if grid[Position::new(cell.position.x, cell.position.y + 1)].pheromone_level != 0.0 {
-                            grid[Position::new(cell.position.x, cell.position.y + 1)].pheromone_level/-50.0
-                        } else {
-                            0.0
-                        }
-                    },
-                    Compass::East => {
-                        if grid[Position::new(cell.position.x + 1, cell.position.y)].pheromone_level != 0.0 {
-                            grid[Position::new(cell.position.x + 1, cell.position.y)].pheromone_level/50.0
-                        } else if grid[Position::new(cell.position.x - 1, cell.position.y)].pheromone_level != 0.0 {
-                            grid[Position::new(cell.position.x - 1, cell.position.y)].pheromone_level/-50.0
-                        } else {
-                            0.0
-                        }
-                    },
-                    Compass::West => {
-                        if grid[Position::new(cell.position.x - 1, cell.position.y)].pheromone_level != 0.0 {
-                            grid[Position::new(cell.position.x - 1, cell.position.y)].pheromone_level/50.0
-                        } else if grid[Position::new(cell.position.x + 1, cell.position.y)].pheromone_level != 0.0 {
-                            grid[Position::new(cell.position.x + 1, cell.position.y)].pheromone_level/-50.0
-                        } else {
-                            0.0
-                        }
-                    },
-                }
-            },
-            Self::PheromoneDensity => {
-                let mut pheromones = 0.0;
-                let pos = cell.position;
-                pheromones += grid[Position::new(pos.x, pos.y)].pheromone_level;
-                pheromones += grid[Position::new(pos.x, pos.y + 1)].pheromone_level;
-                pheromones += grid[Position::new(pos.x + 1, pos.y + 1)].pheromone_level;
-                pheromones += grid[Position::new(pos.x + 1, pos.y)].pheromone_level;
-                pheromones += grid[Position::new(pos.x + 1, pos.y - 1)].pheromone_level;
-                pheromones += grid[Position::new(pos.x, pos.y - 1)].pheromone_level;
-                pheromones += grid[Position::new(pos.x - 1, pos.y + 1)].pheromone_level;
-                pheromones += grid[Position::new(pos.x - 1, pos.y)].pheromone_level;
-                pheromones += grid[Position::new(pos.x - 1, pos.y - 1)].pheromone_level;
-                pheromones/450.0
-            },
-            Self::BlockageLeftRight | Self::PopLeftRight => {
-                if grid[Position::new(cell.position.x + 1, cell.position.y)].cell != null_mut() {
-                    1.0
-                } else if grid[Position::new(cell.position.x - 1, cell.position.y)].cell != null_mut() {
-                    -1.0
-                } else {
-                    0.0
-                }
-            },
-            Self::BlockageUpDown | Self::PopUpDown => {
-                if grid[Position::new(cell.position.x, cell.position.y + 1)].cell != null_mut() {
-                    1.0
-                } else if grid[Position::new(cell.position.x, cell.position.y - 1)].cell != null_mut() {
-                    -1.0
-                } else {
-                    0.0
-                }
-            },
-            Self::BlockageForward | Self::PopForward => {
-                match cell.rotation {
-                    Compass::North => {
-                        if grid[Position::new(cell.position.x, cell.position.y + 1)].cell != null_mut() {
-                            1.0
-                        } else if grid[Position::new(cell.position.x, cell.position.y - 1)].cell != null_mut() {
-                            -1.0
-                        } else {
-                            0.0
-                        }
-                    },
-                    Compass::South => {
-                        if grid[Position::new(cell.position.x, cell.position.y - 1)].cell != null_mut() {
-                            1.0
-                        } else if grid[Position::new(cell.position.x, cell.position.y + 1)].cell != null_mut() {
-                            -1.0
-                        } else {
-                            0.0
-                        }
-                    },
-                    Compass::East => {
-                        if grid[Position::new(cell.position.x + 1, cell.position.y)].cell != null_mut() {
-                            1.0
-                        } else if grid[Position::new(cell.position.x - 1, cell.position.y)].cell != null_mut() {
-                            -1.0
-                        } else {
-                            0.0
-                        }
-                    },
-                    Compass::West => {
-                        if grid[Position::new(cell.position.x - 1, cell.position.y)].cell != null_mut() {
-                            1.0
-                        } else if grid[Position::new(cell.position.x + 1, cell.position.y)].cell != null_mut() {
-                            -1.0
-                        } else {
-                            0.0
-                        }
-                    },
-                }
-            },
+            Self::FoodLeftRight => axis_sense(grid, pos, Compass::East, food),
+            Self::FoodUpDown => vertical_sense(grid, pos, food),
+            Self::FoodForward => axis_sense(grid, pos, forward, food),
+            Self::FoodDensity => density(grid, pos, food),
+            Self::PheromoneLeftRight => axis_sense(grid, pos, Compass::East, pheromone),
+            Self::PheromoneUpDown => vertical_sense(grid, pos, pheromone),
+            Self::PheromoneForward => axis_sense(grid, pos, forward, pheromone),
+            Self::PheromoneDensity => density(grid, pos, pheromone),
+            Self::BlockageLeftRight => axis_sense(grid, pos, Compass::East, blockage),
+            Self::BlockageUpDown => vertical_sense(grid, pos, blockage),
+            Self::BlockageForward => axis_sense(grid, pos, forward, blockage),
+            Self::PopLeftRight => axis_sense(grid, pos, Compass::East, pop),
+            Self::PopUpDown => vertical_sense(grid, pos, pop),
+            Self::PopForward => axis_sense(grid, pos, forward, pop),
             Self::PopDensity => {
-                let mut count = 1.0;
-                let pos = cell.position;
-                count += if grid[Position::new(pos.x, pos.y + 1)].cell != null_mut() {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x + 1, pos.y + 1)].cell != null_mut() {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x + 1, pos.y)].cell != null_mut() {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x + 1, pos.y - 1)].cell != null_mut() {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x, pos.y - 1)].cell != null_mut() {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x - 1, pos.y - 1)].cell != null_mut() {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x - 1, pos.y)].cell != null_mut() {
-                    1.0
-                } else {
-                    0.0
-                };
-                count += if grid[Position::new(pos.x - 1, pos.y + 1)].cell != null_mut() {
-                    1.0
-                } else {
-                    0.0
-                };
-                count/9.0
+                // The cell's own tile is always occupied
+                density(grid, pos, pop)
             },
             Self::LocationX => {
                 ((cell.position.x as f64) * (2.0)/((grid.get_x() - 1) as f64)) - 1.0
@@ -483,12 +315,19 @@ impl InputNeurons {
                 ((cell.position.y as f64) * (2.0)/((grid.get_y() - 1) as f64)) - 1.0
             },
             Self::Age => {
-                // TODO: this
-                0.0
+                // Against the lifespan if the cell has one, otherwise the length of the generation
+                let span = match ctx.lifespan.for_cell(cell) {
+                    Some(span) => span as f64,
+                    None => ctx.generation_length as f64,
+                };
+                if span > 0.0 {
+                    (cell.age as f64 / span).min(1.0)
+                } else {
+                    0.0
+                }
             },
             Self::KillCount => {
-                // TODO: this
-                0.0
+                cell.kills as f64 / (cell.kills as f64 + 1.0)
             },
             Self::LastMoveX => {
                 cell.last_move.x as f64
@@ -497,50 +336,183 @@ impl InputNeurons {
                 cell.last_move.y as f64
             },
             Self::GeneticSimilarity => {
-                // TODO: this
-                0.0
+                // To whoever is in front, 0.0 if nobody is. The cell itself never counts, whatever the edges do
+                let other = grid.neighbour_in(pos, forward)
+                    .and_then(|tile| tile.cell)
+                    .filter(|id| Some(*id) != grid[pos].cell)
+                    .and_then(|id| ctx.cells.get(id));
+                match other {
+                    Some(other) => genome::similarity(&cell.genes, &other.genes),
+                    None => 0.0,
+                }
             },
             Self::Random => {
-                thread_rng().gen_range(-1.0..=1.0)
+                rng.gen_range(-1.0..=1.0)
             },
             Self::Oscilator => {
                 cell.oscilator.get_state()
             },
+            Self::Hunger => {
+                ctx.metabolism.hunger(cell.food_level)
+            },
         }
     }
 }
 
-type Gene = i32;
-
-pub fn encode_gene(input: i32, output: i32, weight: u16, input_is_internal: bool, output_is_internal: bool) -> Gene {
-    let mut ret = ((input & 0x7f) << 24) | ((output & 0x7f) << 16);
-    ret |= if input_is_internal {
-        1 << 31
+// 1.0 if the tile in direction senses something, -1.0 if the one behind does, 0.0 otherwise
+fn axis_sense(grid: &Grid, pos: Position<usize>, direction: Compass, sense: impl Fn(Option<&Tile>) -> f64) -> f64 {
+    let ahead = sense(grid.neighbour_in(pos, direction));
+    if ahead != 0.0 {
+        ahead
     } else {
-        0
-    };
+        -sense(grid.neighbour_in(pos, direction.opposite()))
+    }
+}
 
-    ret |= if output_is_internal {
-        1 << 23
-    } else {
-        0
-    };
+// The hex lattice has no tile straight north, so it averages the two northern diagonals
+fn vertical_sense(grid: &Grid, pos: Position<usize>, sense: impl Fn(Option<&Tile>) -> f64) -> f64 {
+    match grid.get_lattice() {
+        Lattice::Square => axis_sense(grid, pos, Compass::North, sense),
+        Lattice::Hex => (axis_sense(grid, pos, Compass::NorthEast, &sense) + axis_sense(grid, pos, Compass::NorthWest, &sense))/2.0,
+    }
+}
+
+// Mean over the tile and its neighbours
+fn density(grid: &Grid, pos: Position<usize>, sense: impl Fn(Option<&Tile>) -> f64) -> f64 {
+    let mut sum = 0.0;
+    let mut count = 0.0;
+    for tile in grid.neighbourhood(pos) {
+        sum += sense(tile);
+        count += 1.0;
+    }
+    sum/count
+}
+
+/*
+ * Gene layout (format version 2), most significant bit first:
+ *  bit 63      source tag, 1 for an internal neuron, 0 for an input
+ *  bits 48-62  source neuron
+ *  bit 47      sink tag, 1 for an internal neuron, 0 for an output
+ *  bits 32-46  sink neuron
+ *  bits 16-31  weight, what the source's value is multiplied by
+ *  bits 0-15   bias, the sink's sum gets the mean bias of all the genes feeding it
+ * Neuron numbers are exact, a gene naming a number past the last neuron of its kind does nothing (see gene_in_range),
+ * which leaves room for new neurons without changing what existing genes mean. Every bit pattern still decodes.
+ * Mutation keeps genes in range: bit flips only touch the weight and bias, the rewires pick neurons that exist.
+ * Weight and bias are signed 16 bit fixed point numbers with 13 fractional bits,
+ * so -4.0 to just under +4.0 in steps of 1/8192, see weight_to_f64.
+ */
+pub type Gene = u64;
+
+pub const GENE_FORMAT_VERSION: u32 = 2;
+
+/*
+ * Format version 1, kept for reading old genomes:
+ *  bit 31      source is an internal neuron
+ *  bits 24-30  source neuron
+ *  bit 23      sink is an internal neuron
+ *  bits 16-22  sink neuron
+ *  bits 0-15   weight
+ */
+pub type LegacyGene = i32;
+
+const NEURON_MASK: u64 = 0x7fff;
+pub const WEIGHT_BIAS_BITS: u32 = 32; // The low bits of a gene, below the neuron fields
+pub const WEIGHT_SCALE: f64 = 8192.0;
+
+pub fn weight_to_f64(weight: u16) -> f64 {
+    weight as i16 as f64 / WEIGHT_SCALE
+}
 
-    ret | (weight as i32)
+// Saturates outside of -4.0..4.0
+pub fn weight_from_f64(weight: f64) -> u16 {
+    (weight * WEIGHT_SCALE).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16 as u16
 }
 
-pub fn decode_gene(gene: Gene) -> (i32, i32, u16, bool, bool) {
+pub fn encode_gene(input: i32, output: i32, weight: u16, bias: u16, input_is_internal: bool, output_is_internal: bool) -> Gene {
+    ((input_is_internal as u64) << 63)
+        | ((input as u64 & NEURON_MASK) << 48)
+        | ((output_is_internal as u64) << 47)
+        | ((output as u64 & NEURON_MASK) << 32)
+        | ((weight as u64) << 16)
+        | bias as u64
+}
+
+// Uniform over every neuron a gene can connect, with any weight and bias
+pub fn random_gene(rng: &mut impl Rng) -> Gene {
+    let source = rng.gen_range(0..INPUT_NEURON_COUNT + INTERNAL_NEURON_COUNT);
+    let sink = rng.gen_range(0..OUTPUT_NEURON_COUNT + INTERNAL_NEURON_COUNT);
+    let input_is_internal = source >= INPUT_NEURON_COUNT;
+    let output_is_internal = sink >= OUTPUT_NEURON_COUNT;
+    encode_gene(
+        if input_is_internal { source - INPUT_NEURON_COUNT } else { source } as i32,
+        if output_is_internal { sink - OUTPUT_NEURON_COUNT } else { sink } as i32,
+        rng.gen(),
+        rng.gen(),
+        input_is_internal,
+        output_is_internal,
+    )
+}
+
+// Whether both ends of the gene are neurons that exist, genes that aren't do nothing
+pub fn gene_in_range(gene: Gene) -> bool {
+    let (input, output, _, _, input_is_internal, output_is_internal) = decode_gene(gene);
+    let inputs = if input_is_internal { INTERNAL_NEURON_COUNT } else { INPUT_NEURON_COUNT };
+    let outputs = if output_is_internal { INTERNAL_NEURON_COUNT } else { OUTPUT_NEURON_COUNT };
+    (input as usize) < inputs && (output as usize) < outputs
+}
+
+pub fn decode_gene(gene: Gene) -> (i32, i32, u16, u16, bool, bool) {
+    let input_is_internal = (gene >> 63) == 1;
+    let output_is_internal = ((gene >> 47) & 1) == 1;
+    let input = ((gene >> 48) & NEURON_MASK) as i32;
+    let output = ((gene >> 32) & NEURON_MASK) as i32;
+    let weight = (gene >> 16) as u16;
+    let bias = gene as u16;
+
+    (input, output, weight, bias, input_is_internal, output_is_internal)
+}
+
+/*
+ * Every field of the old format fits in the new one, the bias is left at zero.
+ * The old format wrapped neuron numbers around, so they're reduced the same way here and the gene keeps its connection.
+ */
+pub fn from_legacy_gene(gene: LegacyGene) -> Gene {
+    let gene = gene as u32; // Shifting the signed value would smear the source flag over the neuron number
     let input_is_internal = (gene >> 31) == 1;
     let output_is_internal = ((gene >> 23) & 1) == 1;
-    let input: i32 = (gene >> 24) & 0x7f;
-    let output: i32 = (gene >> 16) & 0x7f;
-    let weight: u16 = ((gene) & 0xffff) as u16;
+    let inputs = if input_is_internal { INTERNAL_NEURON_COUNT } else { INPUT_NEURON_COUNT };
+    let outputs = if output_is_internal { INTERNAL_NEURON_COUNT } else { OUTPUT_NEURON_COUNT };
+    encode_gene(
+        ((gene >> 24) & 0x7f) as i32 % inputs as i32,
+        ((gene >> 16) & 0x7f) as i32 % outputs as i32,
+        gene as u16,
+        0,
+        input_is_internal,
+        output_is_internal,
+    )
+}
+
+/*
+ * None if the gene uses anything the old format can't hold: a bias or neuron numbers above 127.
+ * Old genes come back with their neuron numbers reduced, the same connection but not always the same bits.
+ */
+pub fn to_legacy_gene(gene: Gene) -> Option<LegacyGene> {
+    let (input, output, weight, bias, input_is_internal, output_is_internal) = decode_gene(gene);
+    if bias != 0 || input > 0x7f || output > 0x7f {
+        return None;
+    }
 
-    (input, output, weight, input_is_internal, output_is_internal)
+    Some((((input_is_internal as u32) << 31)
+        | ((input as u32) << 24)
+        | ((output_is_internal as u32) << 23)
+        | ((output as u32) << 16)
+        | weight as u32) as LegacyGene)
 }
 
 
 #[derive(Debug,Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Oscilator {
     counter: f64,
     frequency: f64,
@@ -556,7 +528,11 @@ impl Oscilator {
         }
     }
 
-    pub fn update(&mut self) -> () {
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+    }
+
+    pub fn update(&mut self) {
         self.counter += self.frequency;
         if self.counter > 1.0 {
             self.counter = 0.0;
@@ -565,14 +541,46 @@ impl Oscilator {
     }
 }
 
+// How many steps a cell gets before dying of old age
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Lifespan {
+    #[default]
+    Unlimited,
+    Fixed(u32),
+    // Somewhere in min..=max set by the cell's longevity, which is inherited and nudged a little at a time by mutation
+    Genome { min: u32, max: u32 },
+}
+
+impl Lifespan {
+    pub fn for_cell(&self, cell: &Cell) -> Option<u32> {
+        match *self {
+            Self::Unlimited => None,
+            Self::Fixed(span) => Some(span),
+            Self::Genome { min, max } => {
+                let span = max.saturating_sub(min) as f64;
+                Some(min + (cell.longevity.clamp(0.0, 1.0) * span).round() as u32)
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Cell {
     pub genes: Vec<Gene>,
     pub position: Position<usize>,
-    pub last_move: Position<usize>,
+    pub last_move: Position<isize>,
+    pub responsiveness: f64,
     pub food_level: u32,
+    pub kills: u32,
+    pub age: u32, // In steps
     pub rotation: Compass,
     pub oscilator: Oscilator,
+    pub mutations: MutationReport, // What happened to the genome on the way from the parents, empty for founders
+    pub brain: Brain, // Built from genes, use set_genes so the two stay in step
+    pub memory: Vec<f64>, // Internal neuron values from the last step, what recurrent connections read
+    pub longevity: f64, // 0.0 to 1.0, where in the range of Lifespan::Genome this cell's lifespan is
 }
 
 impl Index<usize> for Cell {
@@ -584,27 +592,138 @@ impl Index<usize> for Cell {
 }
 
 impl Cell {
-    pub fn create_cell(gene_count: usize) -> Cell {
+    // With a uniformly random genome
+    pub fn create_cell(gene_count: usize, rng: &mut impl Rng) -> Cell {
+        let genes = (0..gene_count).map(|_| random_gene(rng)).collect();
+        Cell::with_genes(genes, rng)
+    }
+
+    pub fn with_genes(genes: Vec<Gene>, rng: &mut impl Rng) -> Cell {
         Cell {
-            genes: vec![0; gene_count],
+            brain: Brain::new(&genes),
+            memory: Vec::new(),
+            longevity: rng.gen(),
+            genes,
             position: Position { x: 0, y: 0 },
             last_move: Position { x: 0, y: 0 },
             food_level: 10,
-            rotation: match rand::thread_rng().gen::<u8>() % 4 { 0 => Compass::North, 1 => Compass::South, 2 => Compass::East, 3 => Compass::West, _ => Compass::East },
-            oscilator: Oscilator { counter: 0.0, frequency: 0.1, state: false }
+            kills: 0,
+            age: 0,
+            responsiveness: 0.5,
+            rotation: Compass::from_int(rng.gen::<i32>()),
+            oscilator: Oscilator { counter: 0.0, frequency: 0.1, state: false },
+            mutations: MutationReport::default(),
         }
     }
 
-    pub fn generate_offspring(&self) -> Cell {
+    pub fn generate_offspring(&self, mutation: &MutationConfig, rng: &mut impl Rng) -> Cell {
         let mut ret = self.clone();
-        let len = ret.genes.len();
-        
-        if MAGIC_GENE_DECISION_WORD == rand::thread_rng().gen::<u16>() {
-            ret.genes[rand::thread_rng().gen::<usize>() % len] ^= 1 << (rand::thread_rng().gen::<u8>() & 0x1f);
+        ret.mutations = mutation.mutate(&mut ret.genes, rng);
+        if let Some(longevity) = mutation.mutate_longevity(self.longevity, rng) {
+            ret.longevity = longevity;
+            ret.mutations.longevity_perturbations += 1;
         }
+        ret.brain = Brain::new(&ret.genes);
+        ret.memory = Vec::new();
 
         ret.position = self.position;
         ret.last_move = Position::new(0, 0);
         ret
     }
+
+    pub fn set_genes(&mut self, genes: Vec<Gene>) {
+        self.brain = Brain::new(&genes);
+        self.memory = Vec::new();
+        self.genes = genes;
+    }
+
+    // Puts everything except the genome back to how create_cell leaves it
+    pub fn reset_state(&mut self, rng: &mut impl Rng) {
+        let fresh = Cell::with_genes(Vec::new(), rng);
+        self.last_move = fresh.last_move;
+        self.food_level = fresh.food_level;
+        self.kills = fresh.kills;
+        self.age = fresh.age;
+        self.responsiveness = fresh.responsiveness;
+        self.rotation = fresh.rotation;
+        self.oscilator = fresh.oscilator;
+        self.memory = fresh.memory;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_genes_round_trip() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(1);
+        let edges = [0, 1, -1, i32::MIN, i32::MAX, 0x0103_2800, 0x8075_0064u32 as i32];
+        for gene in edges.into_iter().chain((0..10_000).map(|_| rng.gen::<i32>())) {
+            let back = to_legacy_gene(from_legacy_gene(gene)).unwrap();
+            assert_eq!(from_legacy_gene(back), from_legacy_gene(gene), "{:08x}", gene);
+        }
+        // Already in range, nothing to reduce
+        for gene in [0x0103_2800, i32::MIN, 0x8787_ffffu32 as i32] {
+            assert_eq!(to_legacy_gene(from_legacy_gene(gene)), Some(gene), "{:08x}", gene);
+        }
+    }
+
+    #[test]
+    fn legacy_sign_bit_is_the_source_flag() {
+        // An arithmetic shift of the signed gene used to lose this
+        let (input, output, weight, bias, input_is_internal, output_is_internal) = decode_gene(from_legacy_gene(i32::MIN));
+        assert_eq!((input, output, weight, bias, input_is_internal, output_is_internal), (0, 0, 0, 0, true, false));
+
+        let (input, output, weight, bias, input_is_internal, output_is_internal) = decode_gene(from_legacy_gene(-1));
+        assert_eq!((input, output, weight, bias, input_is_internal, output_is_internal), (127 % 8, 127 % 8, 0xffff, 0, true, true));
+    }
+
+    #[test]
+    fn legacy_genomes_keep_their_connections() {
+        // How version 1 genes were read, neuron numbers wrapped by from_int
+        let old = |gene: LegacyGene| -> Gene {
+            let gene = gene as u32;
+            let (input, output) = ((gene >> 24) & 0x7f, (gene >> 16) & 0x7f);
+            let (input_is_internal, output_is_internal) = ((gene >> 31) == 1, ((gene >> 23) & 1) == 1);
+            encode_gene(
+                if input_is_internal { InternalNeurons::from_int(input as i32) as i32 } else { InputNeurons::from_int(input as i32) as i32 },
+                if output_is_internal { InternalNeurons::from_int(output as i32) as i32 } else { OutputNeurons::from_int(output as i32) as i32 },
+                gene as u16,
+                0,
+                input_is_internal,
+                output_is_internal,
+            )
+        };
+
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(2);
+        for _ in 0..200 {
+            let genome: Vec<LegacyGene> = (0..16).map(|_| rng.gen()).collect();
+            let converted: Vec<Gene> = genome.iter().map(|gene| from_legacy_gene(*gene)).collect();
+            assert!(converted.iter().all(|gene| gene_in_range(*gene)));
+            let expected: Vec<Gene> = genome.iter().map(|gene| old(*gene)).collect();
+            assert_eq!(Brain::new(&converted), Brain::new(&expected));
+        }
+    }
+
+    #[test]
+    fn genes_that_need_the_new_format_have_no_legacy_form() {
+        assert_eq!(to_legacy_gene(encode_gene(1, 3, 0x2800, 1, false, false)), None);
+        assert_eq!(to_legacy_gene(encode_gene(0x80, 3, 0x2800, 0, false, false)), None);
+    }
+
+    #[test]
+    fn genome_lifespans_move_gradually() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(3);
+        let lifespan = Lifespan::Genome { min: 100, max: 1100 };
+        let mutation = MutationConfig { rate: 1.0, longevity_perturbation: 1.0, longevity_step: 0.01, ..MutationConfig::default() };
+        let mut cell = Cell::create_cell(8, &mut rng);
+        for _ in 0..100 {
+            let child = cell.generate_offspring(&mutation, &mut rng);
+            let (parent, span) = (lifespan.for_cell(&cell).unwrap(), lifespan.for_cell(&child).unwrap());
+            // Every gene had a bit flipped, the lifespan still only moved by the step
+            assert!(parent.abs_diff(span) <= 10, "{} -> {}", parent, span);
+            cell = child;
+        }
+    }
 }
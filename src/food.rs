@@ -0,0 +1,171 @@
+use crate::cell::*;
+use crate::world::*;
+use std::vec::Vec;
+use rand::*;
+use rand::seq::SliceRandom;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+// Where food tends to appear, both the initial scattering and regrowth follow it
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FoodPattern {
+    Uniform,
+    // Round patches with random centres, richest in the middle, radius in tiles
+    Patches { count: usize, radius: f64 },
+    // Rises linearly from nothing at the opposite edge to full at this one
+    Gradient { towards: Compass },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FoodConfig {
+    pub pattern: FoodPattern,
+    pub initial_density: f64, // Chance of a tile starting a generation with food, scaled by the pattern
+    pub regrowth_rate: f64, // Chance per step of a bare tile growing food, scaled by the pattern
+    pub cap: f64, // Regrowth stops once this fraction of the floor has food
+    pub nutrition: u32, // Added to food_level when eaten
+}
+
+impl Default for FoodConfig {
+    // No food at all unless the map has some
+    fn default() -> Self {
+        FoodConfig {
+            pattern: FoodPattern::Uniform,
+            initial_density: 0.0,
+            regrowth_rate: 0.0,
+            cap: 1.0,
+            nutrition: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Food {
+    config: FoodConfig,
+    weights: Vec<f64>, // Pattern strength per tile, 0.0 on walls
+    map_food: Vec<bool>, // Food the map came with, put back every generation
+    floor: usize,
+}
+
+impl Food {
+    // Whatever food the grid has now is taken as the map's, so only call this on a freshly loaded grid
+    pub fn new(config: FoodConfig, grid: &Grid, rng: &mut impl Rng) -> Food {
+        let map_food = (0..grid.get_y())
+            .flat_map(|y| (0..grid.get_x()).map(move |x| Position::new(x, y)))
+            .map(|pos| grid[pos].has_food)
+            .collect();
+        Food::build(config, grid, map_food, rng)
+    }
+
+    // A new model for the same map, the grid's current food is ignored
+    pub fn reconfigure(&self, config: FoodConfig, grid: &Grid, rng: &mut impl Rng) -> Food {
+        Food::build(config, grid, self.map_food.clone(), rng)
+    }
+
+    fn build(config: FoodConfig, grid: &Grid, map_food: Vec<bool>, rng: &mut impl Rng) -> Food {
+        let (w, h) = (grid.get_x(), grid.get_y());
+        let mut weights = vec![0.0; w * h];
+
+        let centres: Vec<Position<usize>> = match config.pattern {
+            FoodPattern::Patches { count, .. } => {
+                let floor = grid.floor_positions();
+                (0..count).filter(|_| !floor.is_empty()).map(|_| floor[rng.gen::<usize>() % floor.len()]).collect()
+            },
+            _ => Vec::new(),
+        };
+
+        for y in 0..h {
+            for x in 0..w {
+                let tile = &grid[Position::new(x, y)];
+                if tile.is_wall() {
+                    continue;
+                }
+
+                weights[y * w + x] = match config.pattern {
+                    FoodPattern::Uniform => 1.0,
+                    FoodPattern::Patches { radius, .. } => {
+                        centres.iter().map(|c| {
+                            let dx = x as f64 - c.x as f64;
+                            let dy = y as f64 - c.y as f64;
+                            (1.0 - (dx * dx + dy * dy).sqrt() / radius).max(0.0)
+                        }).fold(0.0, f64::max)
+                    },
+                    FoodPattern::Gradient { towards } => {
+                        let fx = (x as f64 + 0.5) / w as f64;
+                        let fy = (y as f64 + 0.5) / h as f64;
+                        match towards {
+                            Compass::North => fy,
+                            Compass::South => 1.0 - fy,
+                            Compass::East => fx,
+                            Compass::West => 1.0 - fx,
+                            Compass::NorthEast => (fx + fy) / 2.0,
+                            Compass::NorthWest => (1.0 - fx + fy) / 2.0,
+                            Compass::SouthEast => (fx + 1.0 - fy) / 2.0,
+                            Compass::SouthWest => (2.0 - fx - fy) / 2.0,
+                        }
+                    },
+                };
+            }
+        }
+
+        Food { config, weights, map_food, floor: grid.floor_positions().len() }
+    }
+
+    pub fn get_config(&self) -> &FoodConfig {
+        &self.config
+    }
+
+    pub fn weight(&self, grid: &Grid, pos: Position<usize>) -> f64 {
+        self.weights[pos.y * grid.get_x() + pos.x]
+    }
+
+    // Clears the grid's food, puts the map's food back and scatters the initial food
+    pub fn seed(&self, grid: &mut Grid, rng: &mut impl Rng) {
+        let w = grid.get_x();
+        for y in 0..grid.get_y() {
+            for x in 0..w {
+                let p = (self.config.initial_density * self.weights[y * w + x]).clamp(0.0, 1.0);
+                grid[Position::new(x, y)].has_food = self.map_food[y * w + x] || (p > 0.0 && rng.gen_bool(p));
+            }
+        }
+    }
+
+    // Tiles are tried in a random order so the cap doesn't favour any part of the grid
+    pub fn regrow(&self, grid: &mut Grid, rng: &mut impl Rng) {
+        if self.config.regrowth_rate <= 0.0 {
+            return;
+        }
+
+        let w = grid.get_x();
+        let cap = (self.config.cap * self.floor as f64) as usize;
+        let mut count = 0;
+        for y in 0..grid.get_y() {
+            for x in 0..w {
+                if grid[Position::new(x, y)].has_food {
+                    count += 1;
+                }
+            }
+        }
+
+        let mut bare: Vec<Position<usize>> = (0..grid.get_y())
+            .flat_map(|y| (0..w).map(move |x| Position::new(x, y)))
+            .filter(|pos| !grid[*pos].has_food && self.weights[pos.y * w + pos.x] > 0.0)
+            .collect();
+        bare.shuffle(rng);
+
+        for pos in bare {
+            if count >= cap {
+                return;
+            }
+
+            let p = (self.config.regrowth_rate * self.weights[pos.y * w + pos.x]).clamp(0.0, 1.0);
+            if rng.gen_bool(p) {
+                grid[pos].has_food = true;
+                count += 1;
+            }
+        }
+    }
+}
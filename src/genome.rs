@@ -0,0 +1,246 @@
+use crate::cell::*;
+use std::{fmt, fs, io, path::Path, vec::Vec};
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+const GENE_BITS: u32 = Gene::BITS;
+
+// Differing bits between two genomes of the same length, None if the lengths differ
+pub fn hamming_distance(a: &[Gene], b: &[Gene]) -> Option<u32> {
+    if a.len() != b.len() {
+        return None;
+    }
+
+    Some(a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum())
+}
+
+/*
+ * Edit distance in bits, tolerant of genes being inserted or deleted.
+ * Genes are aligned Needleman-Wunsch style: matching two genes costs their differing bits,
+ * skipping a gene on either side costs a whole gene's worth of bits.
+ * For genomes of the same length this is never more than the Hamming distance.
+ */
+pub fn aligned_distance(a: &[Gene], b: &[Gene]) -> u32 {
+    let mut prev: Vec<u32> = (0..=b.len() as u32).map(|j| j * GENE_BITS).collect();
+    let mut cur: Vec<u32> = vec![0; b.len() + 1];
+
+    for i in 1..=a.len() {
+        cur[0] = i as u32 * GENE_BITS;
+        for j in 1..=b.len() {
+            let matched = prev[j - 1] + (a[i - 1] ^ b[j - 1]).count_ones();
+            let skip_a = prev[j] + GENE_BITS;
+            let skip_b = cur[j - 1] + GENE_BITS;
+            cur[j] = matched.min(skip_a).min(skip_b);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    prev[b.len()]
+}
+
+// Uses the Hamming distance when it can, 0 is the same genome
+pub fn distance(a: &[Gene], b: &[Gene]) -> u32 {
+    hamming_distance(a, b).unwrap_or_else(|| aligned_distance(a, b))
+}
+
+// 1.0 for identical genomes down to 0.0 for completely different ones
+pub fn similarity(a: &[Gene], b: &[Gene]) -> f64 {
+    let bits = a.len().max(b.len()) as f64 * GENE_BITS as f64;
+    if bits == 0.0 {
+        return 1.0;
+    }
+
+    1.0 - distance(a, b) as f64 / bits
+}
+
+// Symmetric matrix of distance between every pair, in the order the genomes were given
+pub fn distance_matrix(genomes: &[&[Gene]]) -> Vec<Vec<u32>> {
+    let mut ret = vec![vec![0; genomes.len()]; genomes.len()];
+    for i in 0..genomes.len() {
+        for j in (i + 1)..genomes.len() {
+            let d = distance(genomes[i], genomes[j]);
+            ret[i][j] = d;
+            ret[j][i] = d;
+        }
+    }
+
+    ret
+}
+
+// Where the first generation's genomes come from
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Founders {
+    // Every founder gets its own uniformly random genome
+    Random { gene_count: usize },
+    // Copies of one genome, each put through the world's MutationConfig so they aren't all identical
+    Replicated(Vec<Gene>),
+    // Used in order and repeated if there are fewer genomes than cells, e.g. from load_genomes
+    Genomes(Vec<Vec<Gene>>),
+}
+
+/*
+ * Genome files start with a "version N" line giving the gene format, then have one genome per line,
+ * each gene written as its bits in hex separated by whitespace.
+ * Anything after a '#' is a comment, lines with no genes are skipped.
+ * Files without the version line are version 1, whose genes are converted with from_legacy_gene.
+ */
+
+#[derive(Debug)]
+pub enum GenomeError {
+    Io(io::Error),
+    Empty,
+    UnknownVersion { line: usize, found: String },
+    BadGene { line: usize, found: String },
+}
+
+impl fmt::Display for GenomeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "couldn't read genomes: {}", e),
+            Self::Empty => write!(f, "no genomes found"),
+            Self::UnknownVersion { line, found } => write!(f, "unknown gene format version {:?} at line {}", found, line),
+            Self::BadGene { line, found } => write!(f, "{:?} at line {} isn't a gene", found, line),
+        }
+    }
+}
+
+impl std::error::Error for GenomeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for GenomeError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+pub fn load_genomes<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<Gene>>, GenomeError> {
+    parse_genomes(&fs::read_to_string(path)?)
+}
+
+pub fn parse_genomes(text: &str) -> Result<Vec<Vec<Gene>>, GenomeError> {
+    let mut ret = Vec::new();
+    let mut version = None;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace().peekable();
+        if words.peek().is_none() {
+            continue;
+        }
+
+        if version.is_none() {
+            if words.peek() == Some(&"version") {
+                let found = words.nth(1).unwrap_or("").to_string();
+                match found.parse::<u32>() {
+                    Ok(v) if (1..=GENE_FORMAT_VERSION).contains(&v) => version = Some(v),
+                    _ => return Err(GenomeError::UnknownVersion { line: n + 1, found }),
+                }
+                continue;
+            }
+            version = Some(1);
+        }
+
+        let genes = words
+            .map(|word| parse_gene(word, version.unwrap())
+                .ok_or_else(|| GenomeError::BadGene { line: n + 1, found: word.to_string() }))
+            .collect::<Result<Vec<Gene>, GenomeError>>()?;
+        ret.push(genes);
+    }
+
+    if ret.is_empty() {
+        return Err(GenomeError::Empty);
+    }
+    Ok(ret)
+}
+
+fn parse_gene(word: &str, version: u32) -> Option<Gene> {
+    match version {
+        1 => u32::from_str_radix(word, 16).ok().map(|gene| from_legacy_gene(gene as LegacyGene)),
+        _ => u64::from_str_radix(word, 16).ok(),
+    }
+}
+
+// One line in the format parse_genomes reads, after the version line save_genomes writes
+pub fn format_genome(genes: &[Gene]) -> String {
+    genes.iter().map(|gene| format!("{:016x}", gene)).collect::<Vec<String>>().join(" ")
+}
+
+// One gene per line as the network sees it, e.g. "0001000328000000 FoodUpDown -> Move x1.2500 +0.0000"
+pub fn describe_genome(genes: &[Gene]) -> String {
+    genes.iter().map(|gene| describe_gene(*gene) + "\n").collect()
+}
+
+pub fn describe_gene(gene: Gene) -> String {
+    let (input, output, weight, bias, input_is_internal, output_is_internal) = decode_gene(gene);
+    if !gene_in_range(gene) {
+        let source = if input_is_internal { "internal" } else { "input" };
+        let sink = if output_is_internal { "internal" } else { "output" };
+        return format!("{:016x} unused ({} {} -> {} {})", gene, source, input, sink, output);
+    }
+
+    let source = if input_is_internal {
+        format!("{:?}", InternalNeurons::from_int(input))
+    } else {
+        format!("{:?}", InputNeurons::from_int(input))
+    };
+    let sink = if output_is_internal {
+        format!("{:?}", InternalNeurons::from_int(output))
+    } else {
+        format!("{:?}", OutputNeurons::from_int(output))
+    };
+
+    format!("{:016x} {} -> {} x{:.4} {:+.4}", gene, source, sink, weight_to_f64(weight), weight_to_f64(bias))
+}
+
+pub fn save_genomes<P: AsRef<Path>>(path: P, genomes: &[&[Gene]]) -> io::Result<()> {
+    let mut text = format!("version {}\n", GENE_FORMAT_VERSION);
+    text.extend(genomes.iter().map(|genes| format_genome(genes) + "\n"));
+    fs::write(path, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEGACY: [LegacyGene; 3] = [0x0103_2800, -1, i32::MIN];
+
+    fn legacy_genome() -> Vec<Gene> {
+        LEGACY.iter().map(|gene| from_legacy_gene(*gene)).collect()
+    }
+
+    #[test]
+    fn version_1_files_are_converted() {
+        let genomes = parse_genomes("version 1\n01032800 ffffffff 80000000\n").unwrap();
+        assert_eq!(genomes, vec![legacy_genome()]);
+    }
+
+    #[test]
+    fn files_without_a_version_are_version_1() {
+        let genomes = parse_genomes("# old file\n\n01032800 ffffffff 80000000 # comment\n").unwrap();
+        assert_eq!(genomes, vec![legacy_genome()]);
+        let back: Vec<LegacyGene> = genomes[0].iter().map(|gene| to_legacy_gene(*gene).unwrap()).collect();
+        // Neuron numbers come back reduced, the old format wrapped them anyway
+        assert_eq!(back, [0x0103_2800, 0x8787_ffffu32 as i32, i32::MIN]);
+    }
+
+    #[test]
+    fn version_2_round_trips() {
+        let genome = vec![legacy_genome(), vec![encode_gene(300, 3, 0x2800, 0x1000, true, false)]];
+        let text = format!("version {}\n{}\n{}\n", GENE_FORMAT_VERSION, format_genome(&genome[0]), format_genome(&genome[1]));
+        assert_eq!(parse_genomes(&text).unwrap(), genome);
+    }
+
+    #[test]
+    fn bad_files_are_rejected() {
+        assert!(matches!(parse_genomes("version 3\n"), Err(GenomeError::UnknownVersion { line: 1, .. })));
+        assert!(matches!(parse_genomes("01032800 zz\n"), Err(GenomeError::BadGene { line: 1, .. })));
+        assert!(matches!(parse_genomes("# nothing\n"), Err(GenomeError::Empty)));
+    }
+}
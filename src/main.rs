@@ -1,5 +1,18 @@
+#![allow(dead_code)]
+
 mod world;
 mod cell;
+mod arena;
+mod selection;
+mod map;
+mod food;
+mod pheromone;
+mod metabolism;
+mod predation;
+mod genome;
+mod reproduction;
+mod mutation;
+mod brain;
 
 fn main() {
-}
\ No newline at end of file
+}
@@ -0,0 +1,121 @@
+use crate::world::*;
+use std::{fmt, fs, io, path::Path, vec::Vec};
+
+/*
+ * ASCII maps, one character per tile:
+ *  '#' wall
+ *  '.' floor
+ *  'f' floor with food
+ *  'S' spawn zone, new cells are only placed on these if there are any
+ * The first line is the northern edge, matching North being +y.
+ * Every line must have the same length, trailing empty lines are ignored.
+ */
+
+#[derive(Debug)]
+pub enum MapError {
+    Io(io::Error),
+    Empty,
+    Ragged { line: usize, expected: usize, found: usize },
+    UnknownTile { line: usize, column: usize, found: char },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "couldn't read map: {}", e),
+            Self::Empty => write!(f, "map has no tiles"),
+            Self::Ragged { line, expected, found } => write!(f, "line {} is {} tiles wide, expected {}", line, found, expected),
+            Self::UnknownTile { line, column, found } => write!(f, "unknown tile {:?} at line {}, column {}", found, line, column),
+        }
+    }
+}
+
+impl std::error::Error for MapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MapError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+pub fn load_map<P: AsRef<Path>>(path: P) -> Result<Grid, MapError> {
+    parse_map(&fs::read_to_string(path)?)
+}
+
+pub fn parse_map(text: &str) -> Result<Grid, MapError> {
+    let mut rows: Vec<&str> = text.lines().map(|line| line.trim_end_matches('\r')).collect();
+    while rows.last().is_some_and(|row| row.is_empty()) {
+        rows.pop();
+    }
+
+    let width = rows.first().map_or(0, |row| row.chars().count());
+    if width == 0 {
+        return Err(MapError::Empty);
+    }
+
+    let height = rows.len();
+    let mut grid = Grid::init(width, height);
+    for (line, row) in rows.iter().enumerate() {
+        let found = row.chars().count();
+        if found != width {
+            return Err(MapError::Ragged { line: line + 1, expected: width, found });
+        }
+
+        let y = height - 1 - line;
+        for (x, c) in row.chars().enumerate() {
+            let tile = &mut grid[Position::new(x, y)];
+            match c {
+                '#' => tile.kind = TileKind::Wall,
+                '.' => tile.kind = TileKind::Floor,
+                'f' => tile.has_food = true,
+                'S' => tile.kind = TileKind::Spawn,
+                _ => return Err(MapError::UnknownTile { line: line + 1, column: x + 1, found: c }),
+            }
+        }
+    }
+
+    Ok(grid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_first_line_is_the_top_row() {
+        let grid = parse_map("#..\n.f.\n..S\n").unwrap();
+        assert_eq!((grid.get_x(), grid.get_y()), (3, 3));
+        assert!(grid[Position::new(0, 2)].is_wall());
+        assert!(grid[Position::new(1, 1)].has_food);
+        assert_eq!(grid[Position::new(2, 0)].kind, TileKind::Spawn);
+        assert_eq!(grid[Position::new(0, 0)].kind, TileKind::Floor);
+    }
+
+    #[test]
+    fn errors_point_at_the_line_and_column() {
+        assert!(matches!(parse_map("...\n..\n"), Err(MapError::Ragged { line: 2, expected: 3, found: 2 })));
+        assert!(matches!(parse_map("...\n.x.\n"), Err(MapError::UnknownTile { line: 2, column: 2, found: 'x' })));
+        assert!(matches!(parse_map("\n\n"), Err(MapError::Empty)));
+    }
+
+    #[test]
+    fn trailing_blank_lines_and_crlf_are_fine() {
+        let grid = parse_map("#.\r\n.S\r\n\r\n\n").unwrap();
+        assert_eq!((grid.get_x(), grid.get_y()), (2, 2));
+        assert!(grid[Position::new(0, 1)].is_wall());
+        assert_eq!(grid[Position::new(1, 0)].kind, TileKind::Spawn);
+    }
+
+    #[test]
+    fn only_spawn_tiles_are_spawn_positions() {
+        let grid = parse_map("S..\n.#.\n..S\n").unwrap();
+        assert_eq!(grid.spawn_positions(), vec![Position::new(2, 0), Position::new(0, 2)]);
+    }
+}
@@ -0,0 +1,39 @@
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/*
+ * Food costs, all taken out of Cell::food_level.
+ * A cell whose food_level hits zero starves at the end of the step it happened in,
+ * so a cell that pays its last unit moving onto food still gets to eat it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MetabolismConfig {
+    pub basal: u32, // Every step, just for being alive
+    pub move_cost: u32, // Every step the cell actually moved
+    pub kill_cost: u32, // Every kill
+    pub satiation: u32, // food_level at which the Hunger sensor reads 0.0
+}
+
+impl Default for MetabolismConfig {
+    // Free living, nobody starves unless costs are set
+    fn default() -> Self {
+        MetabolismConfig {
+            basal: 0,
+            move_cost: 0,
+            kill_cost: 0,
+            satiation: 10,
+        }
+    }
+}
+
+impl MetabolismConfig {
+    // 0.0 when at or above satiation, 1.0 when out of food
+    pub fn hunger(&self, food_level: u32) -> f64 {
+        if self.satiation == 0 {
+            return 0.0;
+        }
+
+        1.0 - (food_level as f64 / self.satiation as f64).min(1.0)
+    }
+}
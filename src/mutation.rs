@@ -0,0 +1,166 @@
+use crate::cell::*;
+use std::vec::Vec;
+use rand::*;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/*
+ * Every gene of an offspring is picked for mutation with a chance of rate,
+ * then each of the per gene operators fires on it independently with its own probability.
+ * Insertion isn't tied to an existing gene, it's tried once per offspring, and so is the longevity nudge.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MutationConfig {
+    pub rate: f64,
+    pub bit_flip: f64, // One random bit of the weight or bias, neurons only change through the rewires
+    pub weight_perturbation: f64, // Nudges the weight by up to weight_step either way
+    pub bias_perturbation: f64, // The same for the bias
+    pub weight_step: u16, // In the gene's fixed point units, 8192 is 1.0, used for both
+    pub source_rewire: f64, // New random input neuron
+    pub sink_rewire: f64, // New random output neuron
+    pub duplication: f64, // A copy of the gene goes in right after it
+    pub deletion: f64,
+    pub insertion: f64, // Per offspring, a random gene at a random place
+    pub longevity_perturbation: f64, // Per offspring, nudges Cell::longevity by up to longevity_step either way
+    pub longevity_step: f64,
+}
+
+impl Default for MutationConfig {
+    // Single bit flips, roughly one per thousand genes, and a small longevity nudge for one offspring in twenty
+    fn default() -> Self {
+        MutationConfig {
+            rate: 0.001,
+            bit_flip: 1.0,
+            weight_perturbation: 0.0,
+            bias_perturbation: 0.0,
+            weight_step: 0x1000, // 0.5
+            source_rewire: 0.0,
+            sink_rewire: 0.0,
+            duplication: 0.0,
+            deletion: 0.0,
+            insertion: 0.0,
+            longevity_perturbation: 0.05,
+            longevity_step: 0.05,
+        }
+    }
+}
+
+// How many times each operator fired on one offspring
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MutationReport {
+    pub bit_flips: usize,
+    pub weight_perturbations: usize,
+    pub bias_perturbations: usize,
+    pub source_rewires: usize,
+    pub sink_rewires: usize,
+    pub duplications: usize,
+    pub deletions: usize,
+    pub insertions: usize,
+    pub longevity_perturbations: usize,
+}
+
+impl MutationReport {
+    pub fn total(&self) -> usize {
+        self.bit_flips + self.weight_perturbations + self.bias_perturbations + self.source_rewires + self.sink_rewires
+            + self.duplications + self.deletions + self.insertions + self.longevity_perturbations
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total() == 0
+    }
+
+    // For adding up a whole generation
+    pub fn add(&mut self, other: &MutationReport) {
+        self.bit_flips += other.bit_flips;
+        self.weight_perturbations += other.weight_perturbations;
+        self.bias_perturbations += other.bias_perturbations;
+        self.source_rewires += other.source_rewires;
+        self.sink_rewires += other.sink_rewires;
+        self.duplications += other.duplications;
+        self.deletions += other.deletions;
+        self.insertions += other.insertions;
+        self.longevity_perturbations += other.longevity_perturbations;
+    }
+}
+
+impl MutationConfig {
+    pub fn mutate(&self, genes: &mut Vec<Gene>, rng: &mut impl Rng) -> MutationReport {
+        let mut report = MutationReport::default();
+        let mut ret: Vec<Gene> = Vec::with_capacity(genes.len());
+
+        for gene in genes.iter() {
+            if !rng.gen_bool(self.rate.clamp(0.0, 1.0)) {
+                ret.push(*gene);
+                continue;
+            }
+
+            let mut gene = *gene;
+            if rng.gen_bool(self.bit_flip.clamp(0.0, 1.0)) {
+                // A flip in a neuron number would mostly name a neuron that doesn't exist and quietly drop the connection
+                gene ^= 1 << rng.gen_range(0..WEIGHT_BIAS_BITS);
+                report.bit_flips += 1;
+            }
+            if rng.gen_bool(self.weight_perturbation.clamp(0.0, 1.0)) {
+                let (input, output, weight, bias, input_is_internal, output_is_internal) = decode_gene(gene);
+                let weight = self.perturb(weight, rng);
+                gene = encode_gene(input, output, weight, bias, input_is_internal, output_is_internal);
+                report.weight_perturbations += 1;
+            }
+            if rng.gen_bool(self.bias_perturbation.clamp(0.0, 1.0)) {
+                let (input, output, weight, bias, input_is_internal, output_is_internal) = decode_gene(gene);
+                let bias = self.perturb(bias, rng);
+                gene = encode_gene(input, output, weight, bias, input_is_internal, output_is_internal);
+                report.bias_perturbations += 1;
+            }
+            if rng.gen_bool(self.source_rewire.clamp(0.0, 1.0)) {
+                let (_, output, weight, bias, _, output_is_internal) = decode_gene(gene);
+                let (input, _, _, _, input_is_internal, _) = decode_gene(random_gene(rng));
+                gene = encode_gene(input, output, weight, bias, input_is_internal, output_is_internal);
+                report.source_rewires += 1;
+            }
+            if rng.gen_bool(self.sink_rewire.clamp(0.0, 1.0)) {
+                let (input, _, weight, bias, input_is_internal, _) = decode_gene(gene);
+                let (_, output, _, _, _, output_is_internal) = decode_gene(random_gene(rng));
+                gene = encode_gene(input, output, weight, bias, input_is_internal, output_is_internal);
+                report.sink_rewires += 1;
+            }
+
+            if rng.gen_bool(self.deletion.clamp(0.0, 1.0)) {
+                report.deletions += 1;
+                continue;
+            }
+            ret.push(gene);
+            if rng.gen_bool(self.duplication.clamp(0.0, 1.0)) {
+                ret.push(gene);
+                report.duplications += 1;
+            }
+        }
+
+        if rng.gen_bool(self.insertion.clamp(0.0, 1.0)) {
+            let at = rng.gen_range(0..=ret.len());
+            ret.insert(at, random_gene(rng));
+            report.insertions += 1;
+        }
+
+        *genes = ret;
+        report
+    }
+
+    // The offspring's longevity if it gets nudged, kept within 0.0..=1.0
+    pub fn mutate_longevity(&self, longevity: f64, rng: &mut impl Rng) -> Option<f64> {
+        if !rng.gen_bool(self.longevity_perturbation.clamp(0.0, 1.0)) {
+            return None;
+        }
+        let step = self.longevity_step.abs();
+        Some((longevity + rng.gen_range(-step..=step)).clamp(0.0, 1.0))
+    }
+
+    // A fixed point weight or bias moved by up to weight_step, saturating at the ends of the range
+    fn perturb(&self, value: u16, rng: &mut impl Rng) -> u16 {
+        let step = self.weight_step as i32;
+        (value as i16 as i32 + rng.gen_range(-step..=step)).clamp(i16::MIN as i32, i16::MAX as i32) as i16 as u16
+    }
+}
@@ -0,0 +1,73 @@
+use crate::world::*;
+use std::vec::Vec;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PheromoneConfig {
+    pub emission: f64, // Deposited by an EmitPheromone firing at full strength
+    pub max: f64, // No tile holds more than this, the sensors read it as 1.0
+    pub diffusion: f64, // Fraction of a tile's pheromone spread evenly over its neighbours each step
+    pub evaporation: f64, // Fraction of what's left that disappears each step
+}
+
+impl Default for PheromoneConfig {
+    fn default() -> Self {
+        PheromoneConfig {
+            emission: 10.0,
+            max: 50.0,
+            diffusion: 0.1,
+            evaporation: 0.05,
+        }
+    }
+}
+
+impl PheromoneConfig {
+    pub fn emit(&self, tile: &mut Tile, level: f64) {
+        tile.pheromone_level = (tile.pheromone_level + self.emission * level).min(self.max);
+    }
+
+    /*
+     * One step of the field update, everything reads the old levels so the order tiles are visited in doesn't matter.
+     * Shares that would spread into a wall (or off a Topology::Wall edge) stay where they are,
+     * so diffusion on its own never loses any pheromone.
+     */
+    pub fn update(&self, grid: &mut Grid) {
+        let (w, h) = (grid.get_x(), grid.get_y());
+        let mut next: Vec<f64> = vec![0.0; w * h];
+
+        for y in 0..h {
+            for x in 0..w {
+                let pos = Position::new(x, y);
+                let level = grid[pos].pheromone_level;
+                if level == 0.0 {
+                    continue;
+                }
+
+                let offsets = grid.neighbour_offsets(pos);
+                let share = level * self.diffusion / offsets.len() as f64;
+                let mut kept = level;
+                for offset in offsets {
+                    match grid.offset(pos, *offset) {
+                        Some(n) if !grid[n].is_wall() && n != pos => {
+                            next[n.y * w + n.x] += share;
+                            kept -= share;
+                        },
+                        _ => {},
+                    }
+                }
+                next[y * w + x] += kept;
+            }
+        }
+
+        for y in 0..h {
+            for x in 0..w {
+                let level = (next[y * w + x] * (1.0 - self.evaporation)).min(self.max);
+                // Don't keep chasing denormals around the grid forever
+                grid[Position::new(x, y)].pheromone_level = if level < self.max * 1e-6 { 0.0 } else { level };
+            }
+        }
+    }
+}
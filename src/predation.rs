@@ -0,0 +1,22 @@
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/*
+ * KillFoward removes whatever cell is on the tile the killer faces.
+ * With killing disabled KillCount reads 0.0 and KillFoward does nothing.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PredationConfig {
+    pub enabled: bool,
+    pub food_transfer: f64, // Fraction of the victim's food_level the killer gets
+}
+
+impl Default for PredationConfig {
+    fn default() -> Self {
+        PredationConfig {
+            enabled: false,
+            food_transfer: 0.5,
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::cell::*;
+use crate::world::*;
+use crate::genome;
+use std::vec::Vec;
+use rand::*;
+use rand::distributions::WeightedIndex;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+// How offspring get their genome, mutation applies on top of either
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Reproduction {
+    // A copy of a single parent
+    #[default]
+    Asexual,
+    // Each parent is paired with a mate from the other survivors and the child gets a mix of both genomes
+    Sexual { mate: MateSelection, crossover: Crossover },
+}
+
+/*
+ * Who a parent breeds with. The parent itself is never picked unless it's the only survivor.
+ * Positions are where the survivors were when the generation ended.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MateSelection {
+    Random,
+    // Closest by straight line distance, across the edges on a Topology::Wrap grid. Ties go to the first survivor
+    Nearest,
+    // Random, with each candidate weighted by genome::similarity so similar genomes pair up more often
+    Similarity,
+}
+
+impl MateSelection {
+    // Index into survivors of the mate for survivors[parent]
+    pub fn pick(&self, parent: usize, survivors: &[Cell], grid: &Grid, rng: &mut impl Rng) -> usize {
+        if survivors.len() < 2 {
+            return parent;
+        }
+
+        let candidates: Vec<usize> = (0..survivors.len()).filter(|i| *i != parent).collect();
+        match *self {
+            Self::Random => candidates[rng.gen_range(0..candidates.len())],
+            Self::Nearest => {
+                let from = survivors[parent].position;
+                *candidates.iter()
+                    .min_by_key(|i| distance_squared(grid, from, survivors[**i].position))
+                    .unwrap()
+            },
+            Self::Similarity => {
+                let genes = &survivors[parent].genes;
+                let weights: Vec<f64> = candidates.iter().map(|i| genome::similarity(genes, &survivors[*i].genes)).collect();
+                // Every weight being zero is an error for WeightedIndex, fall back to a uniform pick
+                match WeightedIndex::new(&weights) {
+                    Ok(dist) => candidates[rng.sample(dist)],
+                    Err(_) => candidates[rng.gen_range(0..candidates.len())],
+                }
+            },
+        }
+    }
+}
+
+fn distance_squared(grid: &Grid, a: Position<usize>, b: Position<usize>) -> usize {
+    let mut dx = a.x.abs_diff(b.x);
+    let mut dy = a.y.abs_diff(b.y);
+    if grid.get_topology() == Topology::Wrap {
+        dx = dx.min(grid.get_x() - dx);
+        dy = dy.min(grid.get_y() - dy);
+    }
+
+    dx * dx + dy * dy
+}
+
+/*
+ * How two genomes are mixed, genes are never split.
+ * The child is as long as the first parent, when the mate's genome is shorter its missing genes come from the first parent.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Crossover {
+    // Genes before a random cut come from the first parent, the rest from the mate
+    SinglePoint,
+    // Genes between two random cuts come from the mate
+    TwoPoint,
+    // Every gene is picked from either parent with even odds
+    Uniform,
+}
+
+impl Crossover {
+    pub fn apply(&self, a: &[Gene], b: &[Gene], rng: &mut impl Rng) -> Vec<Gene> {
+        let shared = a.len().min(b.len());
+        let (start, end) = match *self {
+            Self::SinglePoint => (rng.gen_range(0..=shared), shared),
+            Self::TwoPoint => {
+                let (x, y) = (rng.gen_range(0..=shared), rng.gen_range(0..=shared));
+                (x.min(y), x.max(y))
+            },
+            Self::Uniform => {
+                return a.iter().enumerate()
+                    .map(|(i, gene)| if i < shared && rng.gen_bool(0.5) { b[i] } else { *gene })
+                    .collect();
+            },
+        };
+
+        a.iter().enumerate()
+            .map(|(i, gene)| if (start..end).contains(&i) { b[i] } else { *gene })
+            .collect()
+    }
+}
@@ -0,0 +1,156 @@
+use crate::cell::*;
+use crate::world::*;
+use std::{fmt::Debug, vec::Vec, boxed::Box};
+
+/*
+ * Decides who gets to reproduce at the end of a generation.
+ * score returns 0.0..=1.0, anything above 0.0 survives unless survives is overridden.
+ */
+pub trait SelectionCriterion: Debug + Send + Sync {
+    fn score(&self, cell: &Cell, grid: &Grid) -> f64;
+
+    fn survives(&self, cell: &Cell, grid: &Grid) -> bool {
+        self.score(cell, grid) > 0.0
+    }
+}
+
+// Rectangle in grid-relative coordinates, (0.0, 0.0) is the south west corner and (1.0, 1.0) the north east one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub min: Position<f64>,
+    pub max: Position<f64>,
+}
+
+impl Region {
+    pub fn new(min: Position<f64>, max: Position<f64>) -> Region {
+        Region { min, max }
+    }
+
+    pub fn east_half() -> Region {
+        Region::new(Position::new(0.5, 0.0), Position::new(1.0, 1.0))
+    }
+
+    pub fn west_half() -> Region {
+        Region::new(Position::new(0.0, 0.0), Position::new(0.5, 1.0))
+    }
+
+    pub fn north_half() -> Region {
+        Region::new(Position::new(0.0, 0.5), Position::new(1.0, 1.0))
+    }
+
+    pub fn south_half() -> Region {
+        Region::new(Position::new(0.0, 0.0), Position::new(1.0, 0.5))
+    }
+}
+
+impl SelectionCriterion for Region {
+    fn score(&self, cell: &Cell, grid: &Grid) -> f64 {
+        // Measure from tile centres so the halves of an odd sized grid don't overlap
+        let x = (cell.position.x as f64 + 0.5) / grid.get_x() as f64;
+        let y = (cell.position.y as f64 + 0.5) / grid.get_y() as f64;
+        if x >= self.min.x && x < self.max.x && y >= self.min.y && y < self.max.y {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+// Within radius (grid-relative) of any corner, cells closer to the corner score higher
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Corners {
+    pub radius: f64,
+}
+
+impl SelectionCriterion for Corners {
+    fn score(&self, cell: &Cell, grid: &Grid) -> f64 {
+        let x = cell.position.x as f64 / (grid.get_x().max(2) - 1) as f64;
+        let y = cell.position.y as f64 / (grid.get_y().max(2) - 1) as f64;
+        let dx = x.min(1.0 - x);
+        let dy = y.min(1.0 - y);
+        let distance = (dx * dx + dy * dy).sqrt();
+        if distance < self.radius {
+            1.0 - distance / self.radius
+        } else {
+            0.0
+        }
+    }
+}
+
+// food_level strictly above the threshold, FoodAbove(0) means "survived with any food"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FoodAbove(pub u32);
+
+impl SelectionCriterion for FoodAbove {
+    fn score(&self, cell: &Cell, _grid: &Grid) -> f64 {
+        if cell.food_level > self.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KillsAtLeast(pub u32);
+
+impl SelectionCriterion for KillsAtLeast {
+    fn score(&self, cell: &Cell, _grid: &Grid) -> f64 {
+        if cell.kills >= self.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+// AND, scores the worst of its criteria
+#[derive(Debug)]
+pub struct All(pub Vec<Box<dyn SelectionCriterion>>);
+
+impl SelectionCriterion for All {
+    fn score(&self, cell: &Cell, grid: &Grid) -> f64 {
+        self.0.iter().map(|c| c.score(cell, grid)).fold(1.0, f64::min)
+    }
+
+    fn survives(&self, cell: &Cell, grid: &Grid) -> bool {
+        self.0.iter().all(|c| c.survives(cell, grid))
+    }
+}
+
+// OR, scores the best of its criteria
+#[derive(Debug)]
+pub struct Any(pub Vec<Box<dyn SelectionCriterion>>);
+
+impl SelectionCriterion for Any {
+    fn score(&self, cell: &Cell, grid: &Grid) -> f64 {
+        self.0.iter().map(|c| c.score(cell, grid)).fold(0.0, f64::max)
+    }
+
+    fn survives(&self, cell: &Cell, grid: &Grid) -> bool {
+        self.0.iter().any(|c| c.survives(cell, grid))
+    }
+}
+
+// Weighted mean of the scores, survives when it reaches the threshold
+#[derive(Debug)]
+pub struct Weighted {
+    pub criteria: Vec<(f64, Box<dyn SelectionCriterion>)>,
+    pub threshold: f64,
+}
+
+impl SelectionCriterion for Weighted {
+    fn score(&self, cell: &Cell, grid: &Grid) -> f64 {
+        let total: f64 = self.criteria.iter().map(|(weight, _)| weight).sum();
+        if total <= 0.0 {
+            return 0.0;
+        }
+
+        self.criteria.iter().map(|(weight, c)| weight * c.score(cell, grid)).sum::<f64>() / total
+    }
+
+    fn survives(&self, cell: &Cell, grid: &Grid) -> bool {
+        let score = self.score(cell, grid);
+        score > 0.0 && score >= self.threshold
+    }
+}
@@ -1,10 +1,23 @@
-#[allow(dead_code)]
-
 use crate::cell::*;
-use std::{vec::Vec, ops::Index, ptr::null_mut};
+use crate::arena::*;
+use crate::selection::*;
+use crate::food::*;
+use crate::pheromone::*;
+use crate::metabolism::*;
+use crate::predation::*;
+use crate::reproduction::*;
+use crate::mutation::*;
+use crate::genome::*;
+use std::{vec::Vec, ops::{Index, IndexMut, Neg}, sync::Arc, thread};
 use rand::*;
+use rand::seq::SliceRandom;
+use rand_chacha::ChaCha8Rng;
 
-#[derive(Debug, Clone, Copy)]
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Position<T> {
     pub x: T,
     pub y: T,
@@ -12,23 +25,98 @@ pub struct Position<T> {
 
 impl<T> Position<T> {
     pub fn new(x: T, y: T) -> Position<T> {
-        Position { x: x, y: y }
+        Position { x, y }
+    }
+}
+
+impl<T: Neg<Output = T>> Neg for Position<T> {
+    type Output = Position<T>;
+
+    fn neg(self) -> Self::Output {
+        Position::new(-self.x, -self.y)
     }
 }
+
+// What happens to anything that would leave the grid
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Topology {
+    // Positions are clamped to the nearest edge tile
+    #[default]
+    Clamp,
+    // Torus, leaving one edge comes back in on the opposite one
+    Wrap,
+    // Outside the grid is a wall, it reads as blocked and can't be entered
+    Wall,
+}
+
+/*
+ * Shape of the tiles.
+ * Hex uses offset rows: odd rows sit half a tile east of even ones, so every tile has an East and West neighbour
+ * plus the four diagonals, and nothing straight north or south.
+ * Wrapping a hex grid only lines up if it has an even number of rows, Grid panics on an odd one.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Lattice {
+    #[default]
+    Square,
+    Hex,
+}
+
+impl Lattice {
+    // Clockwise, starting from the northernmost
+    pub fn directions(&self) -> &'static [Compass] {
+        match *self {
+            Self::Square => &[Compass::North, Compass::East, Compass::South, Compass::West],
+            Self::Hex => &[Compass::NorthEast, Compass::East, Compass::SouthEast, Compass::SouthWest, Compass::West, Compass::NorthWest],
+        }
+    }
+}
+
+const SQUARE_NEIGHBOURS: [Position<isize>; 8] = [
+    Position { x: -1, y: -1 }, Position { x: 0, y: -1 }, Position { x: 1, y: -1 },
+    Position { x: -1, y: 0 }, Position { x: 1, y: 0 },
+    Position { x: -1, y: 1 }, Position { x: 0, y: 1 }, Position { x: 1, y: 1 },
+];
+const HEX_EVEN_NEIGHBOURS: [Position<isize>; 6] = [
+    Position { x: -1, y: -1 }, Position { x: 0, y: -1 },
+    Position { x: -1, y: 0 }, Position { x: 1, y: 0 },
+    Position { x: -1, y: 1 }, Position { x: 0, y: 1 },
+];
+const HEX_ODD_NEIGHBOURS: [Position<isize>; 6] = [
+    Position { x: 0, y: -1 }, Position { x: 1, y: -1 },
+    Position { x: -1, y: 0 }, Position { x: 1, y: 0 },
+    Position { x: 0, y: 1 }, Position { x: 1, y: 1 },
+];
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Grid {
     x: usize,
     y: usize,
+    topology: Topology,
+    lattice: Lattice,
+    eight_way: bool,
     internal: Vec<Tile>,
 }
 
 impl Grid {
-    fn init(xp: usize, yp: usize) -> Grid {
-        Grid {
+    pub fn init(xp: usize, yp: usize) -> Grid {
+        Grid::with_lattice(xp, yp, Lattice::Square)
+    }
+
+    pub fn with_lattice(xp: usize, yp: usize, lattice: Lattice) -> Grid {
+        let ret = Grid {
             x: xp,
             y: yp,
-            internal: vec![Tile { has_food: false, pheromone_level: 0.0, cell: null_mut() }; xp * yp],
-        }
+            topology: Topology::default(),
+            lattice,
+            eight_way: false,
+            internal: vec![Tile { kind: TileKind::Floor, has_food: false, pheromone_level: 0.0, cell: None }; xp * yp],
+        };
+        ret.check_hex_wrap();
+        ret
     }
 
     pub fn get_x(&self) -> usize {
@@ -38,101 +126,1039 @@ impl Grid {
     pub fn get_y(&self) -> usize {
         self.y
     }
+
+    pub fn get_topology(&self) -> Topology {
+        self.topology
+    }
+
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.topology = topology;
+        self.check_hex_wrap();
+    }
+
+    pub fn get_lattice(&self) -> Lattice {
+        self.lattice
+    }
+
+    // Only before the grid goes into a World, cells already facing a direction the new lattice lacks would get stuck
+    pub fn set_lattice(&mut self, lattice: Lattice) {
+        self.lattice = lattice;
+        self.check_hex_wrap();
+    }
+
+    // Across the top and bottom edge the row offsets would disagree, giving the wrong neighbours
+    fn check_hex_wrap(&self) {
+        assert!(
+            !(self.lattice == Lattice::Hex && self.topology == Topology::Wrap && self.y % 2 == 1),
+            "a wrapped hex grid needs an even number of rows, this one has {}", self.y
+        );
+    }
+
+    pub fn get_eight_way(&self) -> bool {
+        self.eight_way
+    }
+
+    // Square lattice only, lets cells face (and turn through) the diagonals too
+    pub fn set_eight_way(&mut self, eight_way: bool) {
+        self.eight_way = eight_way;
+    }
+
+    // The directions a cell can face, clockwise starting from the northernmost
+    pub fn directions(&self) -> &'static [Compass] {
+        match self.lattice {
+            Lattice::Square if self.eight_way => &[
+                Compass::North, Compass::NorthEast, Compass::East, Compass::SouthEast,
+                Compass::South, Compass::SouthWest, Compass::West, Compass::NorthWest,
+            ],
+            lattice => lattice.directions(),
+        }
+    }
+
+    pub fn random_direction(&self, rng: &mut impl Rng) -> Compass {
+        let directions = self.directions();
+        directions[rng.gen_range(0..directions.len())]
+    }
+
+    // The offset of one step in direction, which on the hex lattice depends on the row
+    pub fn direction_offset(&self, pos: Position<usize>, direction: Compass) -> Position<isize> {
+        match self.lattice {
+            Lattice::Square => direction.offset(),
+            Lattice::Hex => {
+                let odd = (pos.y % 2) as isize;
+                match direction {
+                    Compass::East => Position::new(1, 0),
+                    Compass::West => Position::new(-1, 0),
+                    // There is no straight North or South here, they fall back to NorthEast and SouthWest
+                    Compass::NorthEast | Compass::North => Position::new(odd, 1),
+                    Compass::NorthWest => Position::new(odd - 1, 1),
+                    Compass::SouthEast => Position::new(odd, -1),
+                    Compass::SouthWest | Compass::South => Position::new(odd - 1, -1),
+                }
+            },
+        }
+    }
+
+    pub fn neighbour_in(&self, pos: Position<usize>, direction: Compass) -> Option<&Tile> {
+        self.neighbour(pos, self.direction_offset(pos, direction))
+    }
+
+    // Offsets to every adjacent tile, eight on the square lattice and six on the hex one
+    pub fn neighbour_offsets(&self, pos: Position<usize>) -> &'static [Position<isize>] {
+        match self.lattice {
+            Lattice::Square => &SQUARE_NEIGHBOURS,
+            Lattice::Hex if pos.y.is_multiple_of(2) => &HEX_EVEN_NEIGHBOURS,
+            Lattice::Hex => &HEX_ODD_NEIGHBOURS,
+        }
+    }
+
+    // Applies a signed offset according to the topology, None means the result is a wall
+    pub fn offset(&self, pos: Position<usize>, offset: Position<isize>) -> Option<Position<usize>> {
+        let x = pos.x as isize + offset.x;
+        let y = pos.y as isize + offset.y;
+        let (w, h) = (self.x as isize, self.y as isize);
+
+        match self.topology {
+            Topology::Clamp => Some(Position::new(x.clamp(0, w - 1) as usize, y.clamp(0, h - 1) as usize)),
+            Topology::Wrap => Some(Position::new(x.rem_euclid(w) as usize, y.rem_euclid(h) as usize)),
+            Topology::Wall => {
+                if x < 0 || y < 0 || x >= w || y >= h {
+                    None
+                } else {
+                    Some(Position::new(x as usize, y as usize))
+                }
+            },
+        }
+    }
+
+    // None past a Topology::Wall edge, and for a clamped lookup that comes back to pos itself
+    pub fn neighbour(&self, pos: Position<usize>, offset: Position<isize>) -> Option<&Tile> {
+        self.offset(pos, offset).filter(|p| *p != pos).map(|p| &self[p])
+    }
+
+    /*
+     * The tile itself followed by its neighbours, each tile only once even if clamping or wrapping
+     * lands several offsets on it. Past a Topology::Wall edge is None.
+     */
+    pub fn neighbourhood(&self, pos: Position<usize>) -> impl Iterator<Item = Option<&Tile>> {
+        let mut seen = vec![pos];
+        let mut ret = vec![Some(&self[pos])];
+        for offset in self.neighbour_offsets(pos) {
+            match self.offset(pos, *offset) {
+                Some(p) if seen.contains(&p) => {},
+                Some(p) => {
+                    seen.push(p);
+                    ret.push(Some(&self[p]));
+                },
+                None => ret.push(None),
+            }
+        }
+        ret.into_iter()
+    }
+
+    // Every tile that isn't a wall
+    pub fn floor_positions(&self) -> Vec<Position<usize>> {
+        self.positions_where(|tile| !tile.is_wall())
+    }
+
+    // Spawn tiles if the map has any, otherwise the whole floor
+    pub fn spawn_positions(&self) -> Vec<Position<usize>> {
+        if self.internal.iter().any(|tile| tile.kind == TileKind::Spawn) {
+            self.positions_where(|tile| tile.kind == TileKind::Spawn)
+        } else {
+            self.floor_positions()
+        }
+    }
+
+    fn positions_where(&self, f: impl Fn(&Tile) -> bool) -> Vec<Position<usize>> {
+        self.internal.iter().enumerate()
+            .filter(|(_, tile)| f(tile))
+            .map(|(i, _)| Position::new(i % self.x, i / self.x))
+            .collect()
+    }
+
+    fn flat_index(&self, index: Position<usize>) -> usize {
+        assert!(index.x < self.x && index.y < self.y, "position {:?} is outside the {}x{} grid", index, self.x, self.y);
+        self.x * index.y + index.x
+    }
 }
 
+// Indexing takes positions already inside the grid, go through offset for anything relative
 impl Index<Position<usize>> for Grid {
     type Output = Tile;
 
     fn index(&self, index: Position<usize>) -> &Self::Output {
-        let ret = if index.y > self.y || index.x >= self.x {
-            // Make sure we select the correct overflow response
-            if index.y > self.y && index.x < self.x { 
-                self.internal.index(self.x * self.y + index.x)
-            } else if index.y < self.y && index.x >= self.x {
-                self.internal.index(self.x * index.y + (self.x - 1))
-            } else {
-                self.internal.index(self.x * self.y + (self.x - 1))
-            }
-        } else {
-            self.internal.index(self.x * index.y + index.x)
-        };
+        self.internal.index(self.flat_index(index))
+    }
+}
 
-        ret
+impl IndexMut<Position<usize>> for Grid {
+    fn index_mut(&mut self, index: Position<usize>) -> &mut Self::Output {
+        let i = self.flat_index(index);
+        self.internal.index_mut(i)
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TileKind {
+    Floor,
+    Wall, // Never holds a cell or food
+    Spawn, // Floor that new cells are placed on, if a grid has none they go anywhere
+}
+
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tile {
+    pub kind: TileKind,
     pub has_food: bool,
     pub pheromone_level: f64,
-    pub cell: *mut Cell, // ik you're not supposed to use pointers in Rust but whatever.
+    pub cell: Option<CellId>,
+}
+
+impl Tile {
+    pub fn is_wall(&self) -> bool {
+        self.kind == TileKind::Wall
+    }
 }
 
+/*
+ * With the serde feature everything but the selection criterion is serialized, trait objects can't be.
+ * A deserialized World is back on the default Region::east_half, call set_selection again before running it.
+ */
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct World {
-    cell_list: Vec<Cell>,
+    cells: CellArena,
     grid: Grid,
+    population: usize,
+    generation: usize,
+    #[cfg_attr(feature = "serde", serde(skip, default = "default_selection"))] // See the note above
+    selection: Arc<dyn SelectionCriterion>,
+    food: Food,
+    pheromone: PheromoneConfig,
+    metabolism: MetabolismConfig,
+    lifespan: Lifespan,
+    predation: PredationConfig,
+    reproduction: Reproduction,
+    mutation: MutationConfig,
+    schedule: Schedule,
+    threads: usize,
+    generation_length: usize, // Steps in the generation being run, 0 outside run_generation
+    starved: usize, // This generation so far
+    died_of_age: usize,
+    killed: usize,
+    seed: u64,
+    rng: ChaCha8Rng, // Every random decision goes through this so a seed replays a run exactly
+}
+
+fn default_selection() -> Arc<dyn SelectionCriterion> {
+    Arc::new(Region::east_half())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GenerationSummary {
+    pub generation: usize,
+    pub steps: usize,
+    pub population: usize, // Cells alive when the last step finished
+    pub survivors: usize,
+    pub offspring: usize,
+    pub mutations: usize, // Offspring with at least one mutation
+    pub mutation_counts: MutationReport, // Summed over every offspring
+    pub starved: usize,
+    pub died_of_age: usize,
+    pub killed: usize,
+    pub seed: u64,
+}
+
+// How the cells in a step are ordered relative to each other
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Schedule {
+    // Everyone reads the old world, then all the actions are applied and conflicts resolved
+    #[default]
+    Synchronous,
+    // One cell at a time in a fresh random order every step
+    RandomSequential,
+    // One cell at a time in arena slot order
+    FixedSequential,
 }
 
-#[derive(Clone, PartialEq)]
-pub enum GeneInput {
-    Input(f64),
-    Internal(InternalNeurons, usize),
-    Empty,
+// Output of a cell's network for a step, nothing in it has been applied yet
+struct Decision {
+    oscilator: Option<f64>,
+    responsiveness: f64,
+    emit: Option<f64>,
+    direction: Option<Compass>,
+    kill: bool,
+    turn: isize, // Clockwise steps through Grid::directions
+    turn_around: bool,
+    memory: Vec<f64>, // The brain's internal neuron values after this step
+}
+
+// What a cell decided to do to the world in a step
+struct Action {
+    id: CellId,
+    victim: Option<CellId>,
+    target: Option<(Position<usize>, Position<isize>, Compass)>,
+}
+
+// Everything the sensors get to look at besides the cell itself
+pub struct SensorContext<'a> {
+    pub grid: &'a Grid,
+    pub cells: &'a CellArena,
+    pub pheromone: &'a PheromoneConfig,
+    pub metabolism: &'a MetabolismConfig,
+    pub predation: &'a PredationConfig,
+    pub lifespan: Lifespan,
+    pub generation_length: usize,
 }
 
 impl World {
+    // Picks a fresh seed, get_seed returns it so the run can be replayed with new_world_seeded
     pub fn new_world(population: usize, gene_count: usize, x: usize, y: usize) -> World {
+        World::new_world_seeded(population, gene_count, x, y, thread_rng().gen())
+    }
+
+    pub fn new_world_seeded(population: usize, gene_count: usize, x: usize, y: usize, seed: u64) -> World {
+        World::with_grid(population, gene_count, Grid::init(x, y), seed)
+    }
+
+    // For grids built elsewhere, e.g. loaded with map::load_map
+    pub fn with_grid(population: usize, gene_count: usize, grid: Grid, seed: u64) -> World {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
         let mut ret = World {
-            cell_list: vec![Cell::create_cell(gene_count); population],
-            grid: Grid::init(x, y),
+            cells: CellArena::with_capacity(population),
+            food: Food::new(FoodConfig::default(), &grid, &mut rng),
+            grid,
+            pheromone: PheromoneConfig::default(),
+            metabolism: MetabolismConfig::default(),
+            lifespan: Lifespan::default(),
+            predation: PredationConfig::default(),
+            reproduction: Reproduction::default(),
+            mutation: MutationConfig::default(),
+            schedule: Schedule::default(),
+            threads: 1,
+            generation_length: 0,
+            starved: 0,
+            died_of_age: 0,
+            killed: 0,
+            population,
+            generation: 0,
+            selection: default_selection(),
+            seed,
+            rng,
         };
 
-        for cell in ret.cell_list.as_mut_slice() { // I hate Rust mutability rules
-            loop { // Why tf doesnt Rust have do { ... } while?
-                cell.position.x = thread_rng().gen::<usize>() % ret.grid.x;
-                cell.position.y = thread_rng().gen::<usize>() % ret.grid.y;
-                if ret.grid[cell.position].cell != null_mut() { break; }
+        ret.populate(&Founders::Random { gene_count });
+        ret
+    }
+
+    /*
+     * Throws away every cell and starts again from these founders, the generation count carries on.
+     * Replicated founders are mutated with the current MutationConfig, so set that first.
+     */
+    pub fn set_founders(&mut self, founders: &Founders) {
+        for id in self.cells.ids() {
+            self.remove_cell(id);
+        }
+        self.populate(founders);
+    }
+
+    fn populate(&mut self, founders: &Founders) {
+        for i in 0..self.population {
+            let cell = match founders {
+                Founders::Random { gene_count } => Cell::create_cell(*gene_count, &mut self.rng),
+                Founders::Replicated(genes) => {
+                    Cell::with_genes(genes.clone(), &mut self.rng).generate_offspring(&self.mutation, &mut self.rng)
+                },
+                Founders::Genomes(genomes) if genomes.is_empty() => break,
+                Founders::Genomes(genomes) => Cell::with_genes(genomes[i % genomes.len()].clone(), &mut self.rng),
+            };
+            if self.place_cell(cell).is_none() {
+                break;
             }
         }
+    }
 
-        ret
+    // Puts the cell on a random empty spawn tile, returns None if they are all taken
+    pub fn place_cell(&mut self, mut cell: Cell) -> Option<CellId> {
+        let spawns = self.grid.spawn_positions();
+        if spawns.iter().all(|pos| self.grid[*pos].cell.is_some()) {
+            return None;
+        }
+
+        loop { // Why tf doesnt Rust have do { ... } while?
+            cell.position = spawns[self.rng.gen::<usize>() % spawns.len()];
+            if self.grid[cell.position].cell.is_none() { break; }
+        }
+        if !self.grid.directions().contains(&cell.rotation) {
+            cell.rotation = self.grid.random_direction(&mut self.rng);
+        }
+
+        let pos = cell.position;
+        let id = self.cells.insert(cell);
+        self.grid[pos].cell = Some(id);
+        Some(id)
     }
-    
-    pub fn step(&self) -> () {
-        let mut gene_inputs: Vec<Vec<GeneInput>> = Vec::with_capacity(self.cell_list.len());
-        for i in 0..self.cell_list.len() {
-            let cell = self.cell_list.index(i);
-            gene_inputs.push(vec![GeneInput::Empty; 15]);
-            let inputs = gene_inputs.last_mut().unwrap();
-
-            for j in cell.genes.as_slice() {
-                let unpacked = decode_gene(*j);
-                let index = if unpacked.4 {
-                    (unpacked.1 % 8) + 8 
-                } else {
-                    unpacked.1 % 8
-                } as usize;
-                
-                let input = if unpacked.3 {
-                    GeneInput::Internal(InternalNeurons::from_int(unpacked.0), i)
-                } else {
-                    GeneInput::Input(InputNeurons::from_int(unpacked.0).handle(cell, &self.grid))
+
+    // Replaces the food model and rescatters the food straight away
+    pub fn set_food(&mut self, config: FoodConfig) {
+        self.food = self.food.reconfigure(config, &self.grid, &mut self.rng);
+        self.food.seed(&mut self.grid, &mut self.rng);
+    }
+
+    pub fn get_food(&self) -> &Food {
+        &self.food
+    }
+
+    pub fn set_pheromone(&mut self, config: PheromoneConfig) {
+        self.pheromone = config;
+    }
+
+    pub fn get_pheromone(&self) -> &PheromoneConfig {
+        &self.pheromone
+    }
+
+    pub fn set_metabolism(&mut self, config: MetabolismConfig) {
+        self.metabolism = config;
+    }
+
+    pub fn get_metabolism(&self) -> &MetabolismConfig {
+        &self.metabolism
+    }
+
+    // Defaults to Schedule::Synchronous
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule = schedule;
+    }
+
+    pub fn get_schedule(&self) -> Schedule {
+        self.schedule
+    }
+
+    /*
+     * Worker threads for sensing and thinking under Schedule::Synchronous, 0 means one per core.
+     * Results don't depend on this, the same seed gives the same run with any number of threads.
+     */
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = if threads == 0 {
+            thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            threads
+        };
+    }
+
+    pub fn get_threads(&self) -> usize {
+        self.threads
+    }
+
+    // Defaults to Lifespan::Unlimited
+    pub fn set_lifespan(&mut self, lifespan: Lifespan) {
+        self.lifespan = lifespan;
+    }
+
+    pub fn get_lifespan(&self) -> Lifespan {
+        self.lifespan
+    }
+
+    // Killing is off by default
+    pub fn set_predation(&mut self, config: PredationConfig) {
+        self.predation = config;
+    }
+
+    pub fn get_predation(&self) -> &PredationConfig {
+        &self.predation
+    }
+
+    // Defaults to Reproduction::Asexual
+    pub fn set_reproduction(&mut self, reproduction: Reproduction) {
+        self.reproduction = reproduction;
+    }
+
+    pub fn get_reproduction(&self) -> Reproduction {
+        self.reproduction
+    }
+
+    pub fn set_mutation(&mut self, config: MutationConfig) {
+        self.mutation = config;
+    }
+
+    pub fn get_mutation(&self) -> &MutationConfig {
+        &self.mutation
+    }
+
+    pub fn get_seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn get_generation(&self) -> usize {
+        self.generation
+    }
+
+    pub fn get_population(&self) -> usize {
+        self.population
+    }
+
+    // Defaults to the east half of the grid, not serialized so it needs setting again after deserializing
+    pub fn set_selection(&mut self, selection: Arc<dyn SelectionCriterion>) {
+        self.selection = selection;
+    }
+
+    pub fn get_selection(&self) -> &dyn SelectionCriterion {
+        self.selection.as_ref()
+    }
+
+    /*
+     * Runs a whole generation: the given number of steps, then selection.
+     * The survivors are bred round robin until the target population is reached again,
+     * and the offspring are scattered over a freshly cleared grid.
+     * If nobody survives the world is left empty.
+     */
+    pub fn run_generation(&mut self, steps: usize) -> GenerationSummary {
+        self.generation_length = steps;
+        for _ in 0..steps {
+            self.step();
+        }
+
+        let population = self.cells.len();
+        let survivors: Vec<Cell> = self.cells.iter()
+            .filter(|(_, cell)| self.selection.survives(cell, &self.grid))
+            .map(|(_, cell)| cell.clone())
+            .collect();
+
+        self.cells.clear();
+        for tile in self.grid.internal.iter_mut() {
+            tile.cell = None;
+            tile.pheromone_level = 0.0;
+        }
+        self.food.seed(&mut self.grid, &mut self.rng);
+
+        let mut offspring = 0;
+        let mut mutations = 0;
+        let mut mutation_counts = MutationReport::default();
+        if !survivors.is_empty() {
+            for i in 0..self.population {
+                let parent = survivors.index(i % survivors.len());
+                let base = match self.reproduction {
+                    Reproduction::Asexual => parent.clone(),
+                    Reproduction::Sexual { mate, crossover } => {
+                        let mate = &survivors[mate.pick(i % survivors.len(), &survivors, &self.grid, &mut self.rng)];
+                        let mut ret = parent.clone();
+                        ret.set_genes(crossover.apply(&parent.genes, &mate.genes, &mut self.rng));
+                        ret
+                    },
                 };
-                inputs[index] = input; // TODO: Multiple inputs
+                let mut child = base.generate_offspring(&self.mutation, &mut self.rng);
+                child.reset_state(&mut self.rng);
+                if !child.mutations.is_empty() {
+                    mutations += 1;
+                }
+                mutation_counts.add(&child.mutations);
+
+                if self.place_cell(child).is_none() {
+                    break;
+                }
+                offspring += 1;
+            }
+        }
+
+        let ret = GenerationSummary {
+            generation: self.generation,
+            steps,
+            population,
+            survivors: survivors.len(),
+            offspring,
+            mutations,
+            mutation_counts,
+            starved: self.starved,
+            died_of_age: self.died_of_age,
+            killed: self.killed,
+            seed: self.seed,
+        };
+        self.generation += 1;
+        self.generation_length = 0;
+        self.starved = 0;
+        self.died_of_age = 0;
+        self.killed = 0;
+        ret
+    }
+
+    pub fn get_grid(&self) -> &Grid {
+        &self.grid
+    }
+
+    // Defaults to Topology::Clamp
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.grid.set_topology(topology);
+    }
+
+    pub fn get_cells(&self) -> &CellArena {
+        &self.cells
+    }
+
+    pub fn get_cell(&self, id: CellId) -> Option<&Cell> {
+        self.cells.get(id)
+    }
+
+    pub fn cell_at(&self, pos: Position<usize>) -> Option<&Cell> {
+        self.grid[pos].cell.and_then(|id| self.cells.get(id))
+    }
+
+    // Frees the cell's slot and clears its tile, returns None if the cell is already dead
+    pub fn remove_cell(&mut self, id: CellId) -> Option<Cell> {
+        let cell = self.cells.remove(id)?;
+        if self.grid[cell.position].cell == Some(id) {
+            self.grid[cell.position].cell = None;
+        }
+        Some(cell)
+    }
+
+    fn sensor_context(&self) -> SensorContext<'_> {
+        SensorContext {
+            grid: &self.grid,
+            cells: &self.cells,
+            pheromone: &self.pheromone,
+            metabolism: &self.metabolism,
+            predation: &self.predation,
+            lifespan: self.lifespan,
+            generation_length: self.generation_length,
+        }
+    }
+
+    /*
+     * Senses and thinks for every cell in ids against the world as it is now, spread over the worker threads.
+     * Each cell draws from its own RNG stream keyed on its place in ids,
+     * so the result is the same however the cells are split between threads.
+     */
+    fn think_all(&self, ids: &[CellId], step_seed: u64) -> Vec<Decision> {
+        let ctx = self.sensor_context();
+        let work = |start: usize, chunk: &[CellId]| -> Vec<Decision> {
+            chunk.iter().enumerate().map(|(k, id)| {
+                let mut rng = cell_rng(step_seed, start + k);
+                let cell = ctx.cells.get(*id).unwrap();
+                decide(cell, &ctx, &mut rng)
+            }).collect()
+        };
+
+        let threads = self.threads.min(ids.len());
+        if threads <= 1 {
+            return work(0, ids);
+        }
+
+        let chunk_size = ids.len().div_ceil(threads);
+        thread::scope(|scope| {
+            let handles: Vec<_> = ids.chunks(chunk_size).enumerate()
+                .map(|(n, chunk)| {
+                    let work = &work;
+                    scope.spawn(move || work(n * chunk_size, chunk))
+                })
+                .collect();
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+        })
+    }
+
+    /*
+     * Applies everything in a decision that only touches the cell itself,
+     * plus pheromone emission which adds up the same in any order.
+     * Kills and movement are returned for the scheduler to apply.
+     */
+    fn apply(&mut self, id: CellId, decision: Decision) -> Action {
+        let cell = self.cells.get_mut(id).unwrap();
+        if let Some(frequency) = decision.oscilator {
+            cell.oscilator.set_frequency(frequency);
+        }
+        cell.responsiveness = decision.responsiveness;
+        cell.memory = decision.memory;
+        if let Some(level) = decision.emit {
+            self.pheromone.emit(&mut self.grid[cell.position], level);
+        }
+
+        cell.oscilator.update();
+        cell.age += 1;
+        cell.last_move = Position::new(0, 0);
+        cell.food_level = cell.food_level.saturating_sub(self.metabolism.basal);
+
+        // Turning happens before the kill so a cell can turn to face its victim
+        cell.rotation = cell.rotation.turn(self.grid.directions(), decision.turn);
+        if decision.turn_around {
+            cell.rotation = cell.rotation.opposite();
+        }
+
+        let victim = if decision.kill {
+            self.grid.offset(cell.position, self.grid.direction_offset(cell.position, cell.rotation))
+                .and_then(|target| self.grid[target].cell)
+                .filter(|victim| *victim != id)
+        } else {
+            None
+        };
+
+        let target = decision.direction.and_then(|direction| {
+            let offset = self.grid.direction_offset(cell.position, direction);
+            self.grid.offset(cell.position, offset).map(|target| (target, offset, direction))
+        });
+
+        Action { id, victim, target }
+    }
+
+    fn kill(&mut self, killer: CellId, victim: CellId) {
+        if !self.cells.contains(killer) {
+            return;
+        }
+        if let Some(victim) = self.remove_cell(victim) {
+            self.credit_kill(killer, &victim);
+        }
+    }
+
+    fn credit_kill(&mut self, killer: CellId, victim: &Cell) {
+        let Some(cell) = self.cells.get_mut(killer) else {
+            return;
+        };
+        cell.kills += 1;
+        cell.food_level += (victim.food_level as f64 * self.predation.food_transfer) as u32;
+        cell.food_level = cell.food_level.saturating_sub(self.metabolism.kill_cost);
+        self.killed += 1;
+    }
+
+    /*
+     * Only onto an empty floor tile, returns whether the cell moved.
+     * A cell that moves ends up facing the way it went,
+     * unless that's a diagonal it can't face, in which case it keeps its rotation.
+     */
+    fn move_cell(&mut self, id: CellId, target: Position<usize>, offset: Position<isize>, direction: Compass) -> bool {
+        // Also catches a clamped move that ends up on the cell's own tile
+        if self.grid[target].cell.is_some() || self.grid[target].is_wall() {
+            return false;
+        }
+        let Some(cell) = self.cells.get_mut(id) else {
+            return false;
+        };
+
+        self.grid[cell.position].cell = None;
+        // Clamping can swallow one axis of a diagonal move
+        cell.last_move = Position::new(
+            if target.x == cell.position.x { 0 } else { offset.x },
+            if target.y == cell.position.y { 0 } else { offset.y },
+        );
+        cell.position = target;
+        let moved = match self.grid.lattice {
+            Lattice::Square => Compass::from_offset(cell.last_move).unwrap_or(direction),
+            Lattice::Hex => direction,
+        };
+        if self.grid.directions().contains(&moved) {
+            cell.rotation = moved;
+        }
+        cell.food_level = cell.food_level.saturating_sub(self.metabolism.move_cost);
+        self.grid[target].cell = Some(id);
+        true
+    }
+
+    /*
+     * Synchronous: everyone senses the world as it was at the start of the step, then everyone decides.
+     * Every kill decided lands, victims are removed together so two cells killing each other both die.
+     * When several cells go for the same victim one of them is picked at random to get the kill,
+     * a killer that is itself killed gets nothing.
+     * Moves are then checked against the grid as it is before anyone moves,
+     * and when several cells want the same tile one of them is picked at random.
+     */
+    fn step_synchronous(&mut self, ids: &[CellId]) {
+        let step_seed = self.rng.gen::<u64>();
+        let decisions = self.think_all(ids, step_seed);
+        let actions: Vec<Action> = ids.iter().zip(decisions).map(|(id, decision)| self.apply(*id, decision)).collect();
+
+        let mut kills: Vec<(CellId, Vec<CellId>)> = Vec::new();
+        for action in actions.iter() {
+            if let Some(victim) = action.victim {
+                match kills.iter_mut().find(|(id, _)| *id == victim) {
+                    Some((_, killers)) => killers.push(action.id),
+                    None => kills.push((victim, vec![action.id])),
+                }
+            }
+        }
+        let kills: Vec<(CellId, CellId)> = kills.into_iter()
+            .map(|(victim, killers)| {
+                let killer = if killers.len() > 1 { killers[self.rng.gen_range(0..killers.len())] } else { killers[0] };
+                (killer, victim)
+            })
+            .collect();
+        let victims: Vec<(CellId, Cell)> = kills.iter()
+            .filter_map(|(killer, victim)| self.remove_cell(*victim).map(|cell| (*killer, cell)))
+            .collect();
+        for (killer, victim) in victims.iter() {
+            self.credit_kill(*killer, victim);
+        }
+
+        let mut claims: Vec<(usize, &Action)> = actions.iter()
+            .filter(|action| self.cells.contains(action.id))
+            .filter_map(|action| action.target.map(|(target, _, _)| (target.y * self.grid.x + target.x, action)))
+            .filter(|(tile, _)| self.grid.internal[*tile].cell.is_none() && !self.grid.internal[*tile].is_wall())
+            .collect();
+        claims.sort_by_key(|(tile, _)| *tile); // Stable, so claimants stay in slot order
+
+        let mut start = 0;
+        while start < claims.len() {
+            let end = start + claims[start..].iter().take_while(|(tile, _)| *tile == claims[start].0).count();
+            let winner = if end - start > 1 {
+                claims[start + self.rng.gen_range(0..end - start)].1
+            } else {
+                claims[start].1
+            };
+            let (target, offset, direction) = winner.target.unwrap();
+            self.move_cell(winner.id, target, offset, direction);
+            start = end;
+        }
+    }
+
+    /*
+     * Each cell senses, thinks and acts before the next one goes, so later cells see what earlier ones did.
+     * This can't be split over threads, but uses the same per cell RNG streams as the synchronous schedule.
+     */
+    fn step_sequential(&mut self, order: &[CellId]) {
+        let step_seed = self.rng.gen::<u64>();
+        for (i, id) in order.iter().enumerate() {
+            // Killed by someone earlier in the step
+            if !self.cells.contains(*id) {
+                continue;
+            }
+
+            let ctx = self.sensor_context();
+            let cell = self.cells.get(*id).unwrap();
+            let mut rng = cell_rng(step_seed, i);
+            let decision = decide(cell, &ctx, &mut rng);
+            let action = self.apply(*id, decision);
+            if let Some(victim) = action.victim {
+                self.kill(*id, victim);
+            }
+            if let Some((target, offset, direction)) = action.target {
+                self.move_cell(*id, target, offset, direction);
+            }
+        }
+    }
+
+    /*
+     * Runs the cells according to the schedule, then the parts of the step that don't depend on it:
+     * eating, starvation and old age, food regrowth and the pheromone field.
+     */
+    pub fn step(&mut self) {
+        let mut ids = self.cells.ids();
+        match self.schedule {
+            Schedule::Synchronous => self.step_synchronous(&ids),
+            Schedule::RandomSequential => {
+                ids.shuffle(&mut self.rng);
+                self.step_sequential(&ids);
+            },
+            Schedule::FixedSequential => self.step_sequential(&ids),
+        }
+
+        // Anyone who moved onto food or was already standing on it eats it
+        let nutrition = self.food.get_config().nutrition;
+        for (_, cell) in self.cells.iter_mut() {
+            let tile = &mut self.grid[cell.position];
+            if tile.has_food {
+                tile.has_food = false;
+                cell.food_level += nutrition;
             }
         }
 
-        for i in 0..self.cell_list.len() {
-            let cell = self.cell_list.index(i);
-            let inputs = gene_inputs.index(i);
+        for id in ids {
+            let Some(cell) = self.cells.get(id) else {
+                continue;
+            };
+
+            if cell.food_level == 0 {
+                self.remove_cell(id);
+                self.starved += 1;
+            } else if self.lifespan.for_cell(cell).is_some_and(|span| cell.age >= span) {
+                self.remove_cell(id);
+                self.died_of_age += 1;
+            }
+        }
+
+        self.food.regrow(&mut self.grid, &mut self.rng);
+        self.pheromone.update(&mut self.grid);
+    }
+}
+
+// The RNG for one cell's sensing and thinking in one step
+fn cell_rng(step_seed: u64, index: usize) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(step_seed);
+    rng.set_stream(index as u64);
+    rng
+}
+
+// Reads every sensor the cell's brain uses, in the order Brain::evaluate wants them
+fn sense(cell: &Cell, ctx: &SensorContext, rng: &mut impl Rng) -> Vec<f64> {
+    cell.brain.get_inputs().iter().map(|neuron| {
+        if *neuron == InputNeurons::KillCount && !ctx.predation.enabled {
+            return 0.0;
+        }
+        let mut neuron = *neuron;
+        neuron.handle(cell, ctx, rng)
+    }).collect()
+}
+
+// The whole of a cell's turn that only reads the world
+fn decide(cell: &Cell, ctx: &SensorContext, rng: &mut impl Rng) -> Decision {
+    let mut memory = cell.memory.clone();
+    let outputs = cell.brain.evaluate(&sense(cell, ctx, rng), &mut memory);
+    let mut ret = think(cell, &outputs, ctx, rng);
+    ret.memory = memory;
+    ret
+}
+
+fn think(cell: &Cell, outputs: &[(OutputNeurons, f64)], ctx: &SensorContext, rng: &mut impl Rng) -> Decision {
+    let mut decision = Decision {
+        oscilator: None,
+        responsiveness: cell.responsiveness,
+        emit: None,
+        direction: None,
+        kill: false,
+        turn: 0,
+        turn_around: false,
+        memory: Vec::new(),
+    };
+    let mut movement: Position<f64> = Position::new(0.0, 0.0);
 
-            for j in inputs.as_slice() {
-                if *j == GeneInput::Empty {
-                    continue;
+    for (output, level) in outputs.iter().copied() {
+        match output {
+            OutputNeurons::SetOscilator => {
+                decision.oscilator = Some((level + 1.0) / 2.0);
+            },
+            OutputNeurons::EmitPheromone => {
+                if level > 0.0 && rng.gen_bool((level * decision.responsiveness).min(1.0)) {
+                    decision.emit = Some(level);
+                }
+            },
+            OutputNeurons::SetResponsiveness => {
+                decision.responsiveness = (level + 1.0) / 2.0;
+            },
+            OutputNeurons::Move => {
+                let forward = cell.rotation.offset();
+                movement.x += forward.x as f64 * level;
+                movement.y += forward.y as f64 * level;
+            },
+            OutputNeurons::MoveX => {
+                movement.x += level;
+            },
+            OutputNeurons::MoveY => {
+                movement.y += level;
+            },
+            OutputNeurons::MoveRandom => {
+                let direction = ctx.grid.random_direction(rng).offset();
+                movement.x += direction.x as f64 * level.abs();
+                movement.y += direction.y as f64 * level.abs();
+            },
+            OutputNeurons::KillFoward => {
+                if ctx.predation.enabled && level > 0.0 {
+                    decision.kill = rng.gen_bool((level * decision.responsiveness).min(1.0));
+                }
+            },
+            OutputNeurons::TurnLeft => {
+                if level > 0.0 && rng.gen_bool((level * decision.responsiveness).min(1.0)) {
+                    decision.turn -= 1;
                 }
+            },
+            OutputNeurons::TurnRight => {
+                if level > 0.0 && rng.gen_bool((level * decision.responsiveness).min(1.0)) {
+                    decision.turn += 1;
+                }
+            },
+            OutputNeurons::TurnAround => {
+                if level > 0.0 && rng.gen_bool((level * decision.responsiveness).min(1.0)) {
+                    decision.turn_around = true;
+                }
+            },
+        }
+    }
+
+    // Each axis moves one tile with a probability of its level scaled by responsiveness
+    let mut offset: Position<isize> = Position::new(0, 0);
+    if rng.gen_bool((movement.x.abs() * decision.responsiveness).min(1.0)) {
+        offset.x = movement.x.signum() as isize;
+    }
+    if rng.gen_bool((movement.y.abs() * decision.responsiveness).min(1.0)) {
+        offset.y = movement.y.signum() as isize;
+    }
+
+    // Hex tiles have no north or south neighbour, so a vertical move has to pick a diagonal
+    if ctx.grid.get_lattice() == Lattice::Hex && offset.x == 0 && offset.y != 0 {
+        offset.x = if rng.gen_bool(0.5) { 1 } else { -1 };
+    }
+    decision.direction = Compass::from_offset(offset);
 
+    decision
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Food, killing and a cost of living, so most of the step is exercised
+    fn busy_world(seed: u64, threads: usize) -> World {
+        let mut ret = World::new_world_seeded(150, 16, 40, 40, seed);
+        ret.set_food(FoodConfig { initial_density: 0.2, regrowth_rate: 0.01, ..FoodConfig::default() });
+        ret.set_predation(PredationConfig { enabled: true, ..PredationConfig::default() });
+        ret.set_metabolism(MetabolismConfig { move_cost: 1, ..MetabolismConfig::default() });
+        ret.set_mutation(MutationConfig { rate: 0.05, ..MutationConfig::default() });
+        ret.set_threads(threads);
+        ret
+    }
+
+    type Snapshot = Vec<(Position<usize>, Vec<Gene>, u32)>;
+
+    fn snapshot(world: &World) -> Snapshot {
+        world.get_cells().iter().map(|(_, cell)| (cell.position, cell.genes.clone(), cell.food_level)).collect()
+    }
+
+    fn run(world: &mut World) -> (Vec<GenerationSummary>, Snapshot) {
+        let summaries = (0..3).map(|_| world.run_generation(30)).collect();
+        for _ in 0..10 {
+            world.step();
+        }
+        (summaries, snapshot(world))
+    }
 
+    #[test]
+    fn same_seed_replays_exactly() {
+        assert_eq!(run(&mut busy_world(11, 1)), run(&mut busy_world(11, 1)));
+    }
+
+    #[test]
+    fn threads_dont_change_the_result() {
+        let single = run(&mut busy_world(23, 1));
+        assert_eq!(single, run(&mut busy_world(23, 4)));
+        assert_eq!(single, run(&mut busy_world(23, 7)));
+    }
+
+    #[test]
+    fn synchronous_kills_dont_depend_on_slot_order() {
+        // Always as responsive as possible and always killing
+        let genes = vec![
+            encode_gene(0, OutputNeurons::SetResponsiveness as i32, 0, weight_from_f64(3.9), false, false),
+            encode_gene(0, OutputNeurons::KillFoward as i32, 0, weight_from_f64(3.9), false, false),
+        ];
+        for seed in 0..20 {
+            let mut world = World::new_world_seeded(0, 0, 6, 6, seed);
+            world.set_predation(PredationConfig { enabled: true, ..PredationConfig::default() });
+            let mut ids = Vec::new();
+            for (x, rotation) in [(2, Compass::East), (3, Compass::West)] {
+                let mut cell = Cell::with_genes(genes.clone(), &mut world.rng);
+                cell.position = Position::new(x, 2);
+                cell.rotation = rotation;
+                let id = world.cells.insert(cell);
+                world.grid[Position::new(x, 2)].cell = Some(id);
+                ids.push(id);
             }
+
+            world.step();
+            assert!(ids.iter().all(|id| !world.cells.contains(*id)), "seed {}", seed);
         }
     }
+
+    #[test]
+    #[should_panic(expected = "even number of rows")]
+    fn wrapped_hex_grids_need_an_even_height() {
+        let mut grid = Grid::with_lattice(4, 5, Lattice::Hex);
+        grid.set_topology(Topology::Wrap);
+    }
 }